@@ -1,3 +1,14 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 fn main() {
     lalrpop::process_src().unwrap();
+
+    // changes every time the compiler is rebuilt, so that cached programs get invalidated
+    let build_id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    println!("cargo:rustc-env=COMPUTE_BUILD_ID={build_id}");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
 }
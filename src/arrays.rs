@@ -0,0 +1,455 @@
+//! Arrays, and indexing/slicing of arrays and strings
+
+use crate::limits::{Limits, Usage};
+use crate::{Data, Error, RuntimeError};
+use internment::Intern;
+use std::fmt;
+
+/// Number of live arrays below which the heap is never collected
+const MIN_COLLECTION: usize = 1024;
+
+/// Arrays created while running, `Data::Array` holds the index of one of them. The arrays that
+/// can't be reached from the registers anymore are freed by `collect`, and their slot reused.
+#[derive(Debug, Clone)]
+pub struct Heap {
+    // `None` for the freed slots
+    arrays: Vec<Option<Vec<Data>>>,
+    free: Vec<u32>,
+    // number of live arrays that triggers the next collection
+    next_collection: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            arrays: Vec::new(),
+            free: Vec::new(),
+            next_collection: MIN_COLLECTION,
+        }
+    }
+}
+
+impl Heap {
+    fn alloc(&mut self, elements: Vec<Data>) -> Data {
+        match self.free.pop() {
+            Some(id) => {
+                self.arrays[id as usize] = Some(elements);
+                Data::Array(id)
+            }
+            None => {
+                self.arrays.push(Some(elements));
+                Data::Array((self.arrays.len() - 1) as u32)
+            }
+        }
+    }
+
+    pub fn get(&self, id: u32) -> &Vec<Data> {
+        self.arrays[id as usize].as_ref().unwrap()
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> &mut Vec<Data> {
+        self.arrays[id as usize].as_mut().unwrap()
+    }
+
+    fn live(&self) -> usize {
+        self.arrays.len() - self.free.len()
+    }
+
+    /// Frees the arrays that can't be reached from `roots` once enough arrays were created since
+    /// the last collection
+    pub fn collect_if_needed(&mut self, roots: &[&[Data]]) {
+        if self.live() >= self.next_collection {
+            self.collect(roots);
+        }
+    }
+
+    /// Frees the arrays that can't be reached from `roots`, arrays included in other arrays being
+    /// reachable too (cycles are freed as well)
+    pub fn collect(&mut self, roots: &[&[Data]]) {
+        let mut marked = vec![false; self.arrays.len()];
+        let mut pending: Vec<u32> = roots
+            .iter()
+            .flat_map(|x| x.iter())
+            .filter_map(array_id)
+            .collect();
+        while let Some(id) = pending.pop() {
+            if !std::mem::replace(&mut marked[id as usize], true) {
+                pending.extend(self.get(id).iter().filter_map(array_id));
+            }
+        }
+        for (id, array) in self.arrays.iter_mut().enumerate() {
+            if !marked[id] && array.take().is_some() {
+                self.free.push(id as u32);
+            }
+        }
+        self.next_collection = MIN_COLLECTION.max(self.live() * 2);
+    }
+
+    /// Compares two values, arrays being equal when their elements are
+    pub fn equal(&self, a: Data, b: Data) -> bool {
+        self.equal_in(a, b, &mut Vec::new())
+    }
+
+    // `compared` holds the pairs of arrays being compared, which are assumed to be equal when
+    // they are met again (arrays containing themselves)
+    fn equal_in(&self, a: Data, b: Data, compared: &mut Vec<(u32, u32)>) -> bool {
+        let (Data::Array(x), Data::Array(y)) = (a, b) else {
+            return a == b;
+        };
+        if x == y || compared.contains(&(x, y)) {
+            return true;
+        }
+        let (first, second) = (self.get(x), self.get(y));
+        if first.len() != second.len() {
+            return false;
+        }
+        compared.push((x, y));
+        let equal = first
+            .iter()
+            .zip(second)
+            .all(|(a, b)| self.equal_in(*a, *b, compared));
+        compared.pop();
+        equal
+    }
+
+    /// Displays a value, with the elements of arrays
+    pub fn show(&self, x: Data) -> Show<'_> {
+        Show { heap: self, x }
+    }
+
+    // `shown` holds the arrays being displayed, which are displayed as `[...]` when they are met
+    // again (arrays containing themselves)
+    fn fmt(&self, x: Data, f: &mut fmt::Formatter, shown: &mut Vec<u32>) -> fmt::Result {
+        let Data::Array(id) = x else {
+            return write!(f, "{x}");
+        };
+        if shown.contains(&id) {
+            return write!(f, "[...]");
+        }
+        shown.push(id);
+        write!(f, "[")?;
+        for (i, x) in self.get(id).iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match x {
+                Data::String(str) => write!(f, "{:?}", str.as_str())?,
+                other => self.fmt(*other, f, shown)?,
+            }
+        }
+        shown.pop();
+        write!(f, "]")
+    }
+}
+
+fn array_id(x: &Data) -> Option<u32> {
+    match x {
+        Data::Array(id) => Some(*id),
+        _ => None,
+    }
+}
+
+/// A value displayed with `Heap::show`
+pub struct Show<'a> {
+    heap: &'a Heap,
+    x: Data,
+}
+
+impl fmt::Display for Show<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.heap.fmt(self.x, f, &mut Vec::new())
+    }
+}
+
+/// Moves the elements to the heap
+pub fn new(
+    elements: Vec<Data>,
+    heap: &mut Heap,
+    usage: &mut Usage,
+    limits: &Limits,
+) -> Result<Data, RuntimeError> {
+    usage.count_elements(elements.len(), limits)?;
+    Ok(heap.alloc(elements))
+}
+
+/// Turns a (possibly negative) index into a position in a sequence of length `len`
+fn position(index: Data, len: usize) -> Result<usize, Error> {
+    let Data::Number(num) = index else {
+        fail!(format_args!("Indices must be integers, got {index:?}"));
+    };
+    if num.fract() != 0.0 {
+        fail!(format_args!("Indices must be integers, got {num}"));
+    }
+    let position = if num < 0.0 { num + len as f64 } else { num };
+    if position < 0.0 || position >= len as f64 {
+        fail!(format_args!(
+            "Index {num} is out of range for a length of {len}"
+        ));
+    }
+    Ok(position as usize)
+}
+
+/// Turns the (optional and possibly negative) bounds of a slice into a range, clamped to `len`
+fn bounds(start: Data, end: Data, len: usize) -> Result<(usize, usize), Error> {
+    let bound = |x: Data, default: usize| match x {
+        Data::Null => Ok(default),
+        Data::Number(num) if num.fract() == 0.0 => {
+            let num = if num < 0.0 { num + len as f64 } else { num };
+            Ok(num.clamp(0.0, len as f64) as usize)
+        }
+        other => Err(Error::new(
+            format_args!("Slice bounds must be integers, got {other:?}"),
+            None,
+        )),
+    };
+    let start = bound(start, 0)?;
+    Ok((start, bound(end, len)?.max(start)))
+}
+
+pub fn index(
+    target: Data,
+    index: Data,
+    heap: &Heap,
+    usage: &mut Usage,
+    limits: &Limits,
+) -> Result<Data, RuntimeError> {
+    match target {
+        Data::Array(array) => {
+            let array = heap.get(array);
+            Ok(array[position(index, array.len())?])
+        }
+        Data::String(str) => {
+            let c = str
+                .chars()
+                .nth(position(index, str.chars().count())?)
+                .unwrap();
+            usage.count_string(c.len_utf8(), limits)?;
+            Ok(Data::String(Intern::from(c.to_string())))
+        }
+        other => {
+            fail!(format_args!("Cannot index {}", other.type_name()));
+        }
+    }
+}
+
+pub fn slice(
+    target: Data,
+    start: Data,
+    end: Data,
+    heap: &mut Heap,
+    usage: &mut Usage,
+    limits: &Limits,
+) -> Result<Data, RuntimeError> {
+    match target {
+        Data::Array(array) => {
+            let array = heap.get(array);
+            let (start, end) = bounds(start, end, array.len())?;
+            new(array[start..end].to_vec(), heap, usage, limits)
+        }
+        Data::String(str) => {
+            let (start, end) = bounds(start, end, str.chars().count())?;
+            let result: String = str.chars().skip(start).take(end - start).collect();
+            usage.count_string(result.len(), limits)?;
+            Ok(Data::String(Intern::from(result)))
+        }
+        other => {
+            fail!(format_args!("Cannot slice {}", other.type_name()));
+        }
+    }
+}
+
+pub fn set(target: Data, index: Data, value: Data, heap: &mut Heap) -> Result<(), Error> {
+    match target {
+        Data::Array(array) => {
+            let array = heap.get_mut(array);
+            let position = position(index, array.len())?;
+            array[position] = value;
+            Ok(())
+        }
+        other => {
+            fail!(format_args!(
+                "Cannot assign to an index of {}",
+                other.type_name()
+            ));
+        }
+    }
+}
+
+/// Creates the array of the numbers from `start` (included) to `end` (excluded)
+pub fn range(
+    start: Data,
+    end: Data,
+    step: Data,
+    heap: &mut Heap,
+    usage: &mut Usage,
+    limits: &Limits,
+) -> Result<Data, RuntimeError> {
+    let (Data::Number(start), Data::Number(end), Data::Number(step)) = (start, end, step) else {
+        fail!(format_args!(
+            "UNSUPPORTED OPERATION: range({start:?}, {end:?}, {step:?})"
+        ));
+    };
+    if step == 0.0 {
+        fail!("range() step cannot be 0");
+    }
+    let len = ((end - start) / step).ceil().max(0.0);
+    // checked before allocating anything
+    usage.count_elements(len as usize, limits)?;
+    let elements = (0..len as usize)
+        .map(|i| Data::Number(start + i as f64 * step))
+        .collect();
+    Ok(heap.alloc(elements))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(x: f64) -> Data {
+        Data::Number(x)
+    }
+
+    // an array of the numbers `0..len`
+    fn array(heap: &mut Heap, len: usize) -> Data {
+        heap.alloc((0..len).map(|x| num(x as f64)).collect())
+    }
+
+    fn message<T: fmt::Debug>(result: Result<T, RuntimeError>) -> String {
+        match result.unwrap_err() {
+            RuntimeError::Error(e) => e.message,
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn indices() {
+        let (mut heap, limits) = (Heap::default(), Limits::default());
+        let mut usage = Usage::new(&limits);
+        let x = array(&mut heap, 3);
+        let mut get = |i: Data| index(x, i, &heap, &mut usage, &limits);
+        assert_eq!(get(num(1.0)), Ok(num(1.0)));
+        assert_eq!(get(num(-1.0)), Ok(num(2.0)));
+        assert_eq!(
+            message(get(num(3.0))),
+            "Index 3 is out of range for a length of 3"
+        );
+        assert_eq!(
+            message(get(num(-4.0))),
+            "Index -4 is out of range for a length of 3"
+        );
+        assert_eq!(message(get(num(0.5))), "Indices must be integers, got 0.5");
+        assert_eq!(
+            message(get(Data::Null)),
+            "Indices must be integers, got Null"
+        );
+    }
+
+    #[test]
+    fn string_indices() {
+        let (heap, limits) = (Heap::default(), Limits::default());
+        let mut usage = Usage::new(&limits);
+        let text = Data::String(Intern::from(String::from("héllo")));
+        assert_eq!(
+            index(text, num(1.0), &heap, &mut usage, &limits),
+            Ok(Data::String(Intern::from(String::from("é"))))
+        );
+        assert_eq!(
+            message(index(text, num(5.0), &heap, &mut usage, &limits)),
+            "Index 5 is out of range for a length of 5"
+        );
+    }
+
+    #[test]
+    fn slices() {
+        let (mut heap, limits) = (Heap::default(), Limits::default());
+        let mut usage = Usage::new(&limits);
+        let x = array(&mut heap, 5);
+        let mut elements = |start: Data, end: Data| {
+            let Data::Array(id) = slice(x, start, end, &mut heap, &mut usage, &limits).unwrap()
+            else {
+                unreachable!()
+            };
+            heap.get(id).clone()
+        };
+        assert_eq!(elements(num(1.0), num(3.0)), [num(1.0), num(2.0)]);
+        assert_eq!(elements(num(-2.0), Data::Null), [num(3.0), num(4.0)]);
+        // bounds are clamped instead of failing
+        assert_eq!(elements(num(3.0), num(100.0)), [num(3.0), num(4.0)]);
+        assert_eq!(elements(num(4.0), num(1.0)), []);
+        assert_eq!(
+            message(slice(
+                x,
+                num(0.5),
+                Data::Null,
+                &mut heap,
+                &mut usage,
+                &limits
+            )),
+            "Slice bounds must be integers, got Number(0.5)"
+        );
+    }
+
+    #[test]
+    fn assignments() {
+        let mut heap = Heap::default();
+        let x = array(&mut heap, 2);
+        assert!(set(x, num(-1.0), Data::Bool(true), &mut heap).is_ok());
+        assert_eq!(heap.get(0), &[num(0.0), Data::Bool(true)]);
+        assert_eq!(
+            set(x, num(2.0), Data::Null, &mut heap).unwrap_err().message,
+            "Index 2 is out of range for a length of 2"
+        );
+        assert_eq!(
+            set(num(1.0), num(0.0), Data::Null, &mut heap)
+                .unwrap_err()
+                .message,
+            "Cannot assign to an index of Number"
+        );
+    }
+
+    #[test]
+    fn element_limit() {
+        let mut heap = Heap::default();
+        let limits = Limits {
+            max_array_elements: 10,
+            ..Limits::default()
+        };
+        let mut usage = Usage::new(&limits);
+        assert!(range(num(0.0), num(8.0), num(1.0), &mut heap, &mut usage, &limits).is_ok());
+        assert!(matches!(
+            range(num(0.0), num(8.0), num(1.0), &mut heap, &mut usage, &limits),
+            Err(RuntimeError::Limit(_))
+        ));
+    }
+
+    #[test]
+    fn collection() {
+        let mut heap = Heap::default();
+        let kept = array(&mut heap, 1);
+        let inner = array(&mut heap, 1);
+        heap.get_mut(0)[0] = inner;
+        // two arrays containing each other, unreachable
+        let cycle = array(&mut heap, 1);
+        let other = heap.alloc(vec![cycle]);
+        heap.get_mut(2)[0] = other;
+        heap.collect(&[&[kept, num(1.0)]]);
+        assert_eq!(heap.live(), 2);
+        assert_eq!(heap.get(1), &[num(0.0)]);
+        // the freed slots are reused
+        assert_eq!(array(&mut heap, 0), other);
+        assert_eq!(heap.arrays.len(), 4);
+    }
+
+    #[test]
+    fn cycles() {
+        let mut heap = Heap::default();
+        let (a, b) = (array(&mut heap, 2), array(&mut heap, 2));
+        heap.get_mut(0)[1] = a;
+        heap.get_mut(1)[1] = b;
+        assert!(heap.equal(a, b));
+        assert_eq!(heap.show(a).to_string(), "[0, [...]]");
+        heap.get_mut(1)[0] = Data::String(Intern::from(String::from("0")));
+        assert!(!heap.equal(a, b));
+        assert_eq!(heap.show(b).to_string(), "[\"0\", [...]]");
+    }
+}
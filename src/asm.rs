@@ -0,0 +1,517 @@
+//! Textual assembler for hand-written VM programs (`.sasm` files)
+//!
+//! ```text
+//! ; comments start with a semicolon
+//! .const
+//! count = 0          ; named register, with its initial value
+//! limit = 10
+//! one = 1
+//! cond = null        ; registers can be numbers, booleans, "strings" or null
+//!
+//! .code
+//! loop:
+//!     INF count limit cond
+//!     CMP cond end   ; jumps to `end` if `cond` is false
+//!     ADD count one count
+//!     JMP loop
+//! end:
+//!     PRINT count
+//! ```
+//!
+//! Mnemonics are the names of the `Instr` variants (case-insensitive), registers are referred to
+//! by name or by index, and `JMP`/`CMP` targets are labels.
+//!
+//! Functions are declared with `.func <name> <params> <label> <first register> <last register>`:
+//! the code starts at the label, and the registers (parameters first) are saved and restored
+//! around each call. `CALL <name> <first argument> <dest>` calls them, `RET <value>` returns.
+
+use crate::bytecode::{self, Invalid, Program};
+use crate::span::DebugInfo;
+use crate::{literals, methods};
+use crate::{Data, Function, Instr};
+use internment::Intern;
+
+pub const EXTENSION: &str = "sasm";
+
+enum Section {
+    Const,
+    Code,
+}
+
+/// A `.func` declaration whose label and registers haven't been resolved yet
+struct PendingFunc<'a> {
+    line: usize,
+    name: &'a str,
+    params: u16,
+    label: &'a str,
+    registers: (&'a str, &'a str),
+}
+
+/// An instruction whose operands haven't been resolved yet
+struct Pending<'a> {
+    line: usize,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => in_string = !in_string,
+            // skip the escaped character
+            '\\' if in_string => {
+                chars.next();
+            }
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_string(value: &str) -> Option<String> {
+    let inner = value.strip_prefix('"')?.strip_suffix('"')?;
+    literals::unescape(inner, 0).ok()
+}
+
+fn parse_value(value: &str) -> Option<Data> {
+    match value {
+        "true" => Some(Data::Bool(true)),
+        "false" => Some(Data::Bool(false)),
+        "null" => Some(Data::Null),
+        _ if value.starts_with('"') => parse_string(value).map(|x| Data::String(Intern::from(x))),
+        _ => value.parse::<f64>().ok().map(Data::Number),
+    }
+}
+
+fn arity(mnemonic: &str) -> Option<usize> {
+    Some(match mnemonic {
+        "NULL" => 0,
+        "PRINT" | "JMP" | "ARGCOUNT" | "EXIT" | "RET" => 1,
+        "CMP" | "MOV" | "ARG" | "ENV" | "SETENV" | "NEG" | "NOT" | "OPENFILE" | "INPUT"
+        | "RANGE" => 2,
+        "ADD" | "MUL" | "SUB" | "DIV" | "MOD" | "POW" | "EQ" | "NOTEQ" | "SUP" | "SUPEQ"
+        | "INF" | "INFEQ" | "BOOLAND" | "BOOLOR" | "FORRANGE" | "FORIN" | "CALL" | "CALLMETHOD"
+        | "NEWARRAY" | "INDEX" | "SLICE" | "SETINDEX" => 3,
+        _ => return None,
+    })
+}
+
+fn find_register(operand: &str, consts: &[Data], variables: &[(String, u16)]) -> Option<u16> {
+    variables
+        .iter()
+        .find(|(x, _)| x == operand)
+        .map(|(_, id)| *id)
+        .or_else(|| operand.parse::<u16>().ok())
+        .filter(|id| (*id as usize) < consts.len())
+}
+
+fn find_label(label: &str, labels: &[(&str, usize)]) -> Option<usize> {
+    labels.iter().find(|(x, _)| *x == label).map(|(_, x)| *x)
+}
+
+macro_rules! asm_error {
+    ($name: expr, $line: expr, $($msg:tt)*) => {
+        error!(format_args!("{}:{}: {}", $name, $line + 1, format_args!($($msg)*)));
+    };
+}
+
+/// Turns the source of a `.sasm` file into a program that `execute` can run
+pub fn assemble(src: &str, name: &str) -> Program {
+    let mut consts: Vec<Data> = Vec::new();
+    let mut variables: Vec<(String, u16)> = Vec::new();
+    let mut labels: Vec<(&str, usize)> = Vec::new();
+    let mut pending: Vec<Pending> = Vec::new();
+    let mut functions: Vec<PendingFunc> = Vec::new();
+    let mut section = Section::Code;
+
+    for (line_nb, line) in src.lines().enumerate() {
+        let mut line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line {
+            ".const" => {
+                section = Section::Const;
+                continue;
+            }
+            ".code" => {
+                section = Section::Code;
+                continue;
+            }
+            _ if line.starts_with(".func ") => {
+                let parts: Vec<&str> = line.split_whitespace().skip(1).collect();
+                let [func, params, label, first, last] = parts[..] else {
+                    asm_error!(
+                        name,
+                        line_nb,
+                        ".func expects a name, a number of parameters, a label and two registers"
+                    );
+                };
+                let Ok(params) = params.parse::<u16>() else {
+                    asm_error!(name, line_nb, "Invalid number of parameters '{params}'");
+                };
+                if functions.iter().any(|x| x.name == func) {
+                    asm_error!(name, line_nb, "Function '{func}' is already defined");
+                }
+                functions.push(PendingFunc {
+                    line: line_nb,
+                    name: func,
+                    params,
+                    label,
+                    registers: (first, last),
+                });
+                continue;
+            }
+            _ if line.starts_with('.') => {
+                asm_error!(name, line_nb, "Unknown section '{line}'");
+            }
+            _ => {}
+        }
+
+        match section {
+            Section::Const => {
+                let (register, value) = match line.split_once('=') {
+                    Some((register, value)) => (Some(register.trim()), value.trim()),
+                    None => (None, line),
+                };
+                let Some(value) = parse_value(value) else {
+                    asm_error!(name, line_nb, "Invalid value '{value}'");
+                };
+                consts.push(value);
+                if let Some(register) = register {
+                    if variables.iter().any(|(x, _)| x == register) {
+                        asm_error!(name, line_nb, "Register '{register}' is already defined");
+                    }
+                    variables.push((register.to_string(), (consts.len() - 1) as u16));
+                }
+            }
+            Section::Code => {
+                if let Some((label, rest)) = line.split_once(':') {
+                    let label = label.trim();
+                    if labels.iter().any(|(x, _)| *x == label) {
+                        asm_error!(name, line_nb, "Label '{label}' is already defined");
+                    }
+                    labels.push((label, pending.len()));
+                    line = rest.trim();
+                    if line.is_empty() {
+                        continue;
+                    }
+                }
+                let mut parts = line.split(|c: char| c.is_whitespace() || c == ',');
+                let mnemonic = parts.next().unwrap();
+                pending.push(Pending {
+                    line: line_nb,
+                    mnemonic,
+                    operands: parts.filter(|x| !x.is_empty()).collect(),
+                });
+            }
+        }
+    }
+
+    let instructions = pending
+        .iter()
+        .enumerate()
+        .map(|(i, instr)| {
+            let upper = instr.mnemonic.to_uppercase();
+            let Some(arity) = arity(&upper) else {
+                asm_error!(name, instr.line, "Unknown instruction '{}'", instr.mnemonic);
+            };
+            if arity != instr.operands.len() {
+                asm_error!(
+                    name,
+                    instr.line,
+                    "{upper} expects {arity} operands, got {}",
+                    instr.operands.len()
+                );
+            }
+            let register = |operand: &str| -> u16 {
+                let Some(id) = find_register(operand, &consts, &variables) else {
+                    asm_error!(name, instr.line, "Unknown register '{operand}'");
+                };
+                id
+            };
+            // relative distance to a label
+            let offset = |operand: &str| -> isize {
+                let Some(target) = find_label(operand, &labels) else {
+                    asm_error!(name, instr.line, "Unknown label '{operand}'");
+                };
+                target as isize - i as isize
+            };
+            let ops = &instr.operands;
+            match upper.as_str() {
+                "NULL" => Instr::Null,
+                "PRINT" => Instr::Print(register(ops[0])),
+                "JMP" => {
+                    let offset = offset(ops[0]);
+                    Instr::Jmp(offset.unsigned_abs() as u16, offset < 0)
+                }
+                "CMP" => {
+                    let offset = offset(ops[1]);
+                    if offset <= 0 {
+                        asm_error!(name, instr.line, "CMP can only jump forward");
+                    }
+                    Instr::Cmp(register(ops[0]), offset as u16)
+                }
+                "ARGCOUNT" => Instr::ArgCount(register(ops[0])),
+                "EXIT" => Instr::Exit(register(ops[0])),
+                "FORRANGE" | "FORIN" => {
+                    let offset = offset(ops[2]);
+                    if offset <= 0 {
+                        asm_error!(name, instr.line, "{upper} can only jump forward");
+                    }
+                    let (x, y) = (register(ops[0]), register(ops[1]));
+                    if upper == "FORRANGE" {
+                        Instr::ForRange(x, y, offset as u16)
+                    } else {
+                        Instr::ForIn(x, y, offset as u16)
+                    }
+                }
+                "RET" => Instr::Ret(register(ops[0])),
+                "CALL" => {
+                    let Some(func) = functions.iter().position(|x| x.name == ops[0]) else {
+                        asm_error!(name, instr.line, "Unknown function '{}'", ops[0]);
+                    };
+                    Instr::Call(func as u16, register(ops[1]), register(ops[2]))
+                }
+                "CALLMETHOD" => {
+                    let Some((method, _)) = methods::find(ops[1]) else {
+                        asm_error!(name, instr.line, "Unknown method '{}'", ops[1]);
+                    };
+                    Instr::CallMethod(register(ops[0]), method, register(ops[2]))
+                }
+                "MOV" => Instr::Mov(register(ops[0]), register(ops[1])),
+                "ARG" => Instr::Arg(register(ops[0]), register(ops[1])),
+                "NEG" => Instr::Neg(register(ops[0]), register(ops[1])),
+                "NOT" => Instr::Not(register(ops[0]), register(ops[1])),
+                "OPENFILE" => Instr::OpenFile(register(ops[0]), register(ops[1])),
+                "INPUT" => Instr::Input(register(ops[0]), register(ops[1])),
+                "RANGE" => Instr::Range(register(ops[0]), register(ops[1])),
+                "NEWARRAY" => {
+                    let Ok(len) = ops[1].parse::<u16>() else {
+                        asm_error!(name, instr.line, "Invalid number of elements '{}'", ops[1]);
+                    };
+                    Instr::NewArray(register(ops[0]), len, register(ops[2]))
+                }
+                "ENV" => Instr::Env(register(ops[0]), register(ops[1])),
+                "SETENV" => Instr::SetEnv(register(ops[0]), register(ops[1])),
+                op => {
+                    let (x, y, z) = (register(ops[0]), register(ops[1]), register(ops[2]));
+                    match op {
+                        "ADD" => Instr::Add(x, y, z),
+                        "MUL" => Instr::Mul(x, y, z),
+                        "SUB" => Instr::Sub(x, y, z),
+                        "DIV" => Instr::Div(x, y, z),
+                        "MOD" => Instr::Mod(x, y, z),
+                        "POW" => Instr::Pow(x, y, z),
+                        "EQ" => Instr::Eq(x, y, z),
+                        "NOTEQ" => Instr::NotEq(x, y, z),
+                        "SUP" => Instr::Sup(x, y, z),
+                        "SUPEQ" => Instr::SupEq(x, y, z),
+                        "INF" => Instr::Inf(x, y, z),
+                        "INFEQ" => Instr::InfEq(x, y, z),
+                        "BOOLAND" => Instr::BoolAnd(x, y, z),
+                        "BOOLOR" => Instr::BoolOr(x, y, z),
+                        "INDEX" => Instr::Index(x, y, z),
+                        "SLICE" => Instr::Slice(x, y, z),
+                        "SETINDEX" => Instr::SetIndex(x, y, z),
+                        _ => unreachable!(),
+                    }
+                }
+            }
+        })
+        .collect();
+
+    let resolved = functions
+        .iter()
+        .map(|func| {
+            let Some(start) = find_label(func.label, &labels) else {
+                asm_error!(name, func.line, "Unknown label '{}'", func.label);
+            };
+            let (first, last) = func.registers;
+            let (Some(first), Some(last)) = (
+                find_register(first, &consts, &variables),
+                find_register(last, &consts, &variables),
+            ) else {
+                asm_error!(name, func.line, "Unknown register in '{first} {last}'");
+            };
+            if last < first || last - first + 1 < func.params {
+                asm_error!(name, func.line, "Not enough registers for the parameters");
+            }
+            Function {
+                name: func.name.to_string(),
+                params: func.params,
+                start,
+                registers: first..last + 1,
+            }
+        })
+        .collect();
+
+    let program = Program {
+        instructions,
+        consts,
+        variables,
+        functions: resolved,
+        debug: DebugInfo::default(),
+    };
+    // the operands must also fit in the registers, `execute` doesn't check them
+    match bytecode::validate(&program) {
+        Ok(()) => program,
+        Err(Invalid::Operands(i)) => {
+            let instr = &pending[i];
+            asm_error!(
+                name,
+                instr.line,
+                "{} uses registers past the last one",
+                instr.mnemonic.to_uppercase()
+            );
+        }
+        Err(Invalid::Function(f)) => {
+            asm_error!(
+                name,
+                functions[f].line,
+                "Function '{}' is invalid",
+                functions[f].name
+            );
+        }
+        Err(Invalid::Locations) => unreachable!("assembled programs have no source locations"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arrays::Heap;
+    use crate::limits::Limits;
+    use crate::{bytecode, execute, RuntimeError};
+
+    // runs a program and returns its registers
+    fn run(program: &Program) -> Result<Vec<Data>, RuntimeError> {
+        let mut consts = program.consts.clone();
+        execute(
+            &program.instructions,
+            0,
+            &mut consts,
+            &mut Heap::default(),
+            &program.functions,
+            &program.debug,
+            &Limits::default(),
+        )?;
+        Ok(consts)
+    }
+
+    fn register(program: &Program, registers: &[Data], name: &str) -> Data {
+        let (_, id) = program.variables.iter().find(|(x, _)| x == name).unwrap();
+        registers[*id as usize]
+    }
+
+    #[test]
+    fn labels_and_registers() {
+        let program = assemble(
+            "; counts to 10\n.const\ncount = 0\nlimit = 10\none = 1\ncond = null\n\n.code\n\
+             loop:\n    INF count limit cond\n    cmp cond end ; to the end\n    ADD count, one, 0\n\
+             \x20   JMP loop\nend: PRINT count\n",
+            "count.sasm",
+        );
+        assert_eq!(
+            program.instructions,
+            [
+                Instr::Inf(0, 1, 3),
+                Instr::Cmp(3, 3),
+                Instr::Add(0, 2, 0),
+                Instr::Jmp(3, true),
+                Instr::Print(0),
+            ]
+        );
+        assert_eq!(
+            program.consts,
+            [
+                Data::Number(0.0),
+                Data::Number(10.0),
+                Data::Number(1.0),
+                Data::Null
+            ]
+        );
+        let registers = run(&program).unwrap();
+        assert_eq!(register(&program, &registers, "count"), Data::Number(10.0));
+    }
+
+    #[test]
+    fn values() {
+        let program = assemble(
+            ".const\nt = true\ns = \"a;\\\"b\" ; comment\n-2.5\n",
+            "values.sasm",
+        );
+        assert_eq!(
+            program.consts,
+            [
+                Data::Bool(true),
+                Data::String(Intern::from(String::from("a;\"b"))),
+                Data::Number(-2.5),
+            ]
+        );
+        assert_eq!(program.variables.len(), 2);
+    }
+
+    #[test]
+    fn functions() {
+        let program = assemble(
+            ".const\nx = 21\nresult = null\ntwo = 2\nn = null\n\
+             .func double 1 double n n\n.code\n\
+             \x20   CALL double x result\n    JMP end\n\
+             double:\n    MUL n two n\n    RET n\nend:\n",
+            "double.sasm",
+        );
+        assert_eq!(program.functions[0].start, 2);
+        assert_eq!(program.functions[0].registers, 3..4);
+        let registers = run(&program).unwrap();
+        assert_eq!(register(&program, &registers, "result"), Data::Number(42.0));
+        // the registers of the function are restored after the call
+        assert_eq!(register(&program, &registers, "n"), Data::Null);
+    }
+
+    #[test]
+    fn arrays() {
+        let program = assemble(
+            ".const\na = 1\nb = 2\nc = 3\narray = null\ni = 1\nx = null\n.code\n\
+             \x20   NEWARRAY a 3 array\n    SETINDEX array i c\n    INDEX array i x\n",
+            "arrays.sasm",
+        );
+        let registers = run(&program).unwrap();
+        assert_eq!(register(&program, &registers, "x"), Data::Number(3.0));
+        let program = assemble(
+            ".const\nempty = null\nlen = 0\n.code\nNEWARRAY empty 0 empty\nINDEX empty len len\n",
+            "bounds.sasm",
+        );
+        let Err(RuntimeError::Error(e)) = run(&program) else {
+            panic!("out of range index");
+        };
+        assert_eq!(e.message, "Index 0 is out of range for a length of 0");
+    }
+
+    #[test]
+    fn invalid_loop_state() {
+        let program = assemble(
+            ".const\ntext = \"abc\"\nstate = null\nc = null\n.code\nFORIN text state end\nend:\n",
+            "loop.sasm",
+        );
+        let Err(RuntimeError::Error(e)) = run(&program) else {
+            panic!("invalid state");
+        };
+        assert_eq!(e.message, "Invalid for-in loop state Null");
+    }
+
+    #[test]
+    fn pow_mod_example() {
+        let program = assemble(include_str!("../examples/pow_mod.sasm"), "pow_mod.sasm");
+        // what compiled programs go through before being cached
+        let program = bytecode::decode(&bytecode::encode(&program)).unwrap();
+        let registers = run(&program).unwrap();
+        assert_eq!(
+            register(&program, &registers, "result"),
+            Data::Number(109376.0)
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use crate::methods::METHODS;
+use crate::span::DebugInfo;
+use crate::{Data, Function, Instr};
+use serde::{Deserialize, Serialize};
+
+/// Every compiled program starts with these bytes
+pub const MAGIC: &[u8; 6] = b"SPOCKC";
+/// Bump whenever `Instr`, `Data` or `Program` change in a way that breaks older files
+pub const FORMAT_VERSION: u16 = 4;
+pub const EXTENSION: &str = "spockc";
+
+/// A compiled program, ready to be run by `execute`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Program {
+    pub instructions: Vec<Instr>,
+    // initial value of every register
+    pub consts: Vec<Data>,
+    // variable names and their register, kept for debugging purposes
+    pub variables: Vec<(String, u16)>,
+    pub functions: Vec<Function>,
+    // location of the instructions in the source code, for error messages
+    pub debug: DebugInfo,
+}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut out = Vec::from(*MAGIC);
+    out.extend(FORMAT_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut out, program).unwrap();
+    out
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Program, String> {
+    if !is_bytecode(bytes) || bytes.len() < MAGIC.len() + 2 {
+        return Err(String::from("is not a compiled Spock program"));
+    }
+    let version = u16::from_le_bytes([bytes[MAGIC.len()], bytes[MAGIC.len() + 1]]);
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "uses bytecode format version {version}, but this version of compute only supports version {FORMAT_VERSION}"
+        ));
+    }
+    let program: Program = bincode::deserialize(&bytes[MAGIC.len() + 2..])
+        .map_err(|_| String::from("is corrupted"))?;
+    validate(&program).map_err(|e| match e {
+        Invalid::Operands(i) => {
+            format!("is corrupted (instruction {} has invalid operands)", i + 1)
+        }
+        Invalid::Function(f) => format!(
+            "is corrupted (function {} is invalid)",
+            program.functions[f].name
+        ),
+        Invalid::Locations => String::from("is corrupted (invalid source locations)"),
+    })?;
+    Ok(program)
+}
+
+/// Part of a program found invalid by `validate`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Invalid {
+    // index of the instruction
+    Operands(usize),
+    // index of the function
+    Function(usize),
+    Locations,
+}
+
+/// Checks that the operands of a program refer to existing registers, instructions, functions
+/// and methods, so that a corrupted file is rejected instead of crashing `execute`
+pub fn validate(program: &Program) -> Result<(), Invalid> {
+    let len = program.instructions.len();
+    // `count` consecutive registers starting at `first`
+    let registers = |first: u16, count: usize| first as usize + count <= program.consts.len();
+    let reg = |x: u16| registers(x, 1);
+    for (i, instr) in program.instructions.iter().enumerate() {
+        // jumps can go right after the last instruction, which ends the program
+        let forward = |size: u16| i + size as usize <= len;
+        let valid = match *instr {
+            Instr::Null => true,
+            Instr::Print(x) | Instr::ArgCount(x) | Instr::Exit(x) | Instr::Ret(x) => reg(x),
+            Instr::Jmp(size, true) => size as usize <= i,
+            Instr::Jmp(size, false) => forward(size),
+            Instr::Cmp(x, size) => reg(x) && forward(size),
+            Instr::Mov(x, y)
+            | Instr::Arg(x, y)
+            | Instr::Env(x, y)
+            | Instr::SetEnv(x, y)
+            | Instr::Neg(x, y)
+            | Instr::Not(x, y)
+            | Instr::OpenFile(x, y)
+            | Instr::Input(x, y) => reg(x) && reg(y),
+            Instr::Add(x, y, z)
+            | Instr::Mul(x, y, z)
+            | Instr::Sub(x, y, z)
+            | Instr::Div(x, y, z)
+            | Instr::Mod(x, y, z)
+            | Instr::Pow(x, y, z)
+            | Instr::Eq(x, y, z)
+            | Instr::NotEq(x, y, z)
+            | Instr::Sup(x, y, z)
+            | Instr::SupEq(x, y, z)
+            | Instr::Inf(x, y, z)
+            | Instr::InfEq(x, y, z)
+            | Instr::BoolAnd(x, y, z)
+            | Instr::BoolOr(x, y, z)
+            | Instr::Index(x, y, z)
+            | Instr::SetIndex(x, y, z) => reg(x) && reg(y) && reg(z),
+            Instr::ForRange(base, var, size) => registers(base, 3) && reg(var) && forward(size),
+            Instr::ForIn(iterable, state, size) => {
+                reg(iterable) && registers(state, 2) && forward(size)
+            }
+            Instr::Call(function, args, dest) => program
+                .functions
+                .get(function as usize)
+                .is_some_and(|func| registers(args, func.params as usize) && reg(dest)),
+            Instr::CallMethod(base, method, dest) => METHODS
+                .get(method as usize)
+                .is_some_and(|(_, _, arity)| registers(base, 1 + arity) && reg(dest)),
+            Instr::NewArray(base, count, dest) => registers(base, count as usize) && reg(dest),
+            Instr::Slice(target, bounds, dest) => reg(target) && registers(bounds, 2) && reg(dest),
+            Instr::Range(base, dest) => registers(base, 3) && reg(dest),
+        };
+        if !valid {
+            return Err(Invalid::Operands(i));
+        }
+    }
+    for (f, func) in program.functions.iter().enumerate() {
+        let (lo, hi) = (func.registers.start, func.registers.end);
+        if func.start > len
+            || lo > hi
+            || !registers(lo, (hi - lo) as usize)
+            || hi - lo < func.params
+        {
+            return Err(Invalid::Function(f));
+        }
+    }
+    let files = program.debug.files.len();
+    if program
+        .debug
+        .locations
+        .iter()
+        .any(|x| x.file as usize >= files)
+    {
+        return Err(Invalid::Locations);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::Location;
+    use internment::Intern;
+
+    fn program() -> Program {
+        Program {
+            instructions: vec![
+                Instr::Add(0, 1, 2),
+                Instr::Call(0, 2, 3),
+                Instr::Cmp(4, 2),
+                Instr::Jmp(3, true),
+                Instr::Print(3),
+                Instr::Ret(2),
+            ],
+            consts: vec![
+                Data::Number(1.0),
+                Data::Number(2.0),
+                Data::Null,
+                Data::String(Intern::new(String::from("text"))),
+                Data::Bool(false),
+            ],
+            variables: vec![(String::from("x"), 0), (String::from("y"), 1)],
+            functions: vec![Function {
+                name: String::from("f"),
+                params: 1,
+                start: 5,
+                registers: 2..3,
+            }],
+            debug: DebugInfo {
+                files: vec![String::from("main.spock")],
+                locations: vec![
+                    Location {
+                        file: 0,
+                        line: 1,
+                        column: 1
+                    };
+                    6
+                ],
+            },
+        }
+    }
+
+    // `program` changed by `f`, encoded then decoded
+    fn round_trip(f: impl FnOnce(&mut Program)) -> Result<Program, String> {
+        let mut program = program();
+        f(&mut program);
+        decode(&encode(&program))
+    }
+
+    #[test]
+    fn encode_decode() {
+        let bytes = encode(&program());
+        assert!(is_bytecode(&bytes));
+        assert_eq!(decode(&bytes), Ok(program()));
+    }
+
+    #[test]
+    fn other_files() {
+        assert_eq!(
+            decode(b"print(1);"),
+            Err(String::from("is not a compiled Spock program"))
+        );
+        let mut bytes = encode(&program());
+        bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(decode(&bytes).unwrap_err().contains("format version"));
+        let bytes = encode(&program());
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 3]),
+            Err(String::from("is corrupted"))
+        );
+    }
+
+    #[test]
+    fn invalid_operands() {
+        let error = |i: usize, instr: Instr| round_trip(|x| x.instructions[i] = instr).unwrap_err();
+        let expected =
+            |i: usize| format!("is corrupted (instruction {} has invalid operands)", i + 1);
+        // register out of range
+        assert_eq!(error(0, Instr::Add(0, 1, 5)), expected(0));
+        // unknown function
+        assert_eq!(error(1, Instr::Call(1, 2, 3)), expected(1));
+        // jumps past the end or before the start
+        assert_eq!(error(2, Instr::Cmp(4, 5)), expected(2));
+        assert_eq!(error(3, Instr::Jmp(4, true)), expected(3));
+        // unknown method
+        assert_eq!(error(4, Instr::CallMethod(0, u16::MAX, 1)), expected(4));
+        // jumping right after the last instruction ends the program
+        assert!(round_trip(|x| x.instructions[2] = Instr::Cmp(4, 4)).is_ok());
+    }
+
+    #[test]
+    fn invalid_functions_and_locations() {
+        assert_eq!(
+            round_trip(|x| x.functions[0].registers = 2..6),
+            Err(String::from("is corrupted (function f is invalid)"))
+        );
+        assert_eq!(
+            round_trip(|x| x.functions[0].params = 2),
+            Err(String::from("is corrupted (function f is invalid)"))
+        );
+        assert_eq!(
+            round_trip(|x| x.debug.locations[0].file = 1),
+            Err(String::from("is corrupted (invalid source locations)"))
+        );
+    }
+}
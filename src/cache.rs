@@ -0,0 +1,82 @@
+use crate::bytecode::{self, Program};
+use crate::cli::Input;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CACHE_DIR: &str = ".compute";
+
+/// A compiled program stored in the cache
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    // every imported file and the hash of its contents at compile time
+    imports: Vec<(PathBuf, String)>,
+    program: Vec<u8>,
+}
+
+pub fn hash(contents: &[u8]) -> String {
+    blake3::hash(contents).to_string()
+}
+
+/// Where the imports of the input are resolved from: the file itself, or the current directory for
+/// the standard input
+fn origin(input: &Input) -> PathBuf {
+    let path = match input {
+        Input::File(path) => fs::canonicalize(path),
+        Input::Stdin => std::env::current_dir(),
+    };
+    path.unwrap_or_default()
+}
+
+/// Cache key of a source file: any change to the compiler or the bytecode format invalidates it,
+/// and the same code in another place gets its own entry as its imports may differ
+fn key(source: &str, input: &Input) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.update(env!("COMPUTE_BUILD_ID").as_bytes());
+    hasher.update(&bytecode::FORMAT_VERSION.to_le_bytes());
+    hasher.update(origin(input).as_os_str().as_encoded_bytes());
+    hasher.update(source.as_bytes());
+    hasher.finalize().to_string()
+}
+
+fn is_up_to_date(imports: &[(PathBuf, String)]) -> bool {
+    imports
+        .iter()
+        .all(|(path, old)| fs::read(path).is_ok_and(|contents| &hash(&contents) == old))
+}
+
+/// Returns the cached program for `source`, if it exists and none of its imports changed
+pub fn load(source: &str, input: &Input) -> Option<Program> {
+    let data = fs::read(Path::new(CACHE_DIR).join(key(source, input))).ok()?;
+    // a corrupted entry is simply compiled again
+    let entry: Entry = bincode::deserialize(&data).ok()?;
+    if !is_up_to_date(&entry.imports) {
+        return None;
+    }
+    bytecode::decode(&entry.program).ok()
+}
+
+pub fn store(source: &str, input: &Input, program: &Program, imports: Vec<(PathBuf, String)>) {
+    let entry = Entry {
+        imports,
+        program: bytecode::encode(program),
+    };
+    // failing to write the cache shouldn't prevent the program from running
+    if fs::create_dir_all(CACHE_DIR).is_ok() {
+        let _ = fs::write(
+            Path::new(CACHE_DIR).join(key(source, input)),
+            bincode::serialize(&entry).unwrap(),
+        );
+    }
+}
+
+pub fn clear() {
+    if Path::new(CACHE_DIR).exists() {
+        fs::remove_dir_all(CACHE_DIR).unwrap_or_else(|_| {
+            error!(format_args!(
+                "Failed to delete the cache folder ({CACHE_DIR})"
+            ));
+        });
+    }
+}
@@ -0,0 +1,242 @@
+use crate::limits::{Limits, EXIT_LIMIT};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Exit status used when the command line itself is invalid.
+pub const EXIT_USAGE: i32 = 2;
+
+const BANNER: &str = r"
+  ______   ______   .___  ___. .______    __    __  .___________. _______
+ /      | /  __  \  |   \/   | |   _  \  |  |  |  | |           ||   ____|
+|  ,----'|  |  |  | |  \  /  | |  |_)  | |  |  |  | `---|  |----`|  |__
+|  |     |  |  |  | |  |\/|  | |   ___/  |  |  |  |     |  |     |   __|
+|  `----.|  `--'  | |  |  |  | |  |      |  `--'  |     |  |     |  |____
+ \______| \______/  |__|  |__| | _|       \______/      |__|     |_______|
+";
+
+/// Where the source code should be read from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    File(PathBuf),
+    Stdin,
+}
+
+impl Input {
+    pub fn name(&self) -> String {
+        match self {
+            Input::File(path) => path.display().to_string(),
+            Input::Stdin => String::from("<stdin>"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run(Input),
+    Check(Input),
+    Disasm(Input),
+    Asm(Input),
+    // input -- output file
+    Build(Input, Option<PathBuf>),
+    Repl,
+    Help,
+    Version,
+    // only flags were given (e.g. `compute --clear-cache`)
+    Nothing,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cli {
+    pub command: Command,
+    // everything after the input file, forwarded to the script
+    pub script_args: Vec<String>,
+    pub timings: bool,
+    // print the compiler's and the VM's debugging output
+    pub log: bool,
+    pub clear_cache: bool,
+    pub no_cache: bool,
+    pub limits: Limits,
+}
+
+pub fn print_banner() {
+    println!(
+        "{BANNER}
+\x1b[3mLive long and prosper!\x1b[0m\n- Spock
+
+To run a file, run: `compute <file>`
+To get help, run `compute -h`"
+    );
+}
+
+pub fn print_help() {
+    println!(
+        "{BANNER}
+\x1b[3mHelp me, Obi-Wan Kenobi. You’re my only hope.\x1b[0m\n- Princess Leia
+
+usage: compute [options] <command> [args...]
+
+commands:
+  run <file> [args...]     Run a script, forwarding [args...] to it
+  check <file>             Parse and compile a script without running it
+  disasm <file>            List the instructions a script compiles to
+  asm <file> [args...]     Assemble and run a hand-written VM program (.sasm)
+  build <file> [-o <out>]  Compile a script to bytecode (<file>.spockc by default)
+  repl                     Start an interactive session
+  <file> [args...]         Shorthand for `run <file> [args...]`
+  help                     Show this message
+
+  <file> can be `-` to read the script from the standard input
+  `run`, `check`, `disasm` and `build` also accept compiled (.spockc) and assembly (.sasm) files
+
+options:
+  -c, --clear-cache        Delete the cache folder (.compute)
+      --no-cache           Always compile the script, without reading or writing the cache
+      --timings            Print parsing and execution times
+      --log                Print the parsed code, instructions and registers to stderr
+  -h, --help               Show this message
+  -V, --version            Show the version
+
+limits (only the call depth is limited by default):
+      --max-instructions <n>   Stop after executing <n> instructions
+      --timeout <seconds>      Stop after running for <seconds>
+      --max-registers <n>      Refuse to run programs that use more than <n> registers
+      --max-string-bytes <n>   Stop once the strings created while running exceed <n> bytes
+      --max-array-elements <n> Stop once the arrays created while running exceed <n> elements
+      --max-depth <n>          Stop when more than <n> function calls are nested (default: 10000)
+
+exit codes:
+  0  success
+  1  the script failed to compile or run
+  2  invalid command line
+  {EXIT_LIMIT}  the script exceeded one of its limits"
+    );
+}
+
+pub fn usage_error(msg: &str) -> ! {
+    eprintln!("\u{001b}[31merror:\u{001b}[0m {msg}\nTo get help, run `compute -h`");
+    std::process::exit(EXIT_USAGE);
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    let Some(value) = value else {
+        return Err(format!("`{flag}` expects a value"));
+    };
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value `{value}` for `{flag}`"))
+}
+
+fn parse_input(arg: &str) -> Input {
+    if arg == "-" {
+        Input::Stdin
+    } else {
+        Input::File(PathBuf::from(arg))
+    }
+}
+
+pub fn parse_args(args: &[String]) -> Result<Cli, String> {
+    let mut cli = Cli {
+        command: Command::Nothing,
+        script_args: Vec::new(),
+        timings: false,
+        log: false,
+        clear_cache: false,
+        no_cache: false,
+        limits: Limits::default(),
+    };
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-h" | "--help" | "help" => {
+                cli.command = Command::Help;
+                return Ok(cli);
+            }
+            "-V" | "--version" => {
+                cli.command = Command::Version;
+                return Ok(cli);
+            }
+            "-c" | "--clear-cache" => cli.clear_cache = true,
+            "--no-cache" => cli.no_cache = true,
+            "--timings" => cli.timings = true,
+            "--log" => cli.log = true,
+            "--max-instructions" => cli.limits.max_instructions = parse_value(arg, iter.next())?,
+            "--max-registers" => cli.limits.max_registers = parse_value(arg, iter.next())?,
+            "--max-string-bytes" => cli.limits.max_string_bytes = parse_value(arg, iter.next())?,
+            "--max-array-elements" => {
+                cli.limits.max_array_elements = parse_value(arg, iter.next())?
+            }
+            "--max-depth" => cli.limits.max_depth = parse_value(arg, iter.next())?,
+            "--timeout" => {
+                let secs: f64 = parse_value(arg, iter.next())?;
+                cli.limits.timeout = Some(
+                    Duration::try_from_secs_f64(secs)
+                        .map_err(|_| format!("Invalid value `{secs}` for `{arg}`"))?,
+                );
+            }
+            "run" | "check" | "disasm" | "asm" => {
+                let Some(file) = iter.next() else {
+                    return Err(format!("`{arg}` expects a file"));
+                };
+                let input = parse_input(file);
+                cli.command = match arg.as_str() {
+                    "run" => Command::Run(input),
+                    "check" => Command::Check(input),
+                    "asm" => Command::Asm(input),
+                    _ => Command::Disasm(input),
+                };
+                break;
+            }
+            "build" => {
+                let Some(file) = iter.next() else {
+                    return Err(String::from("`build` expects a file"));
+                };
+                let mut output = None;
+                if let Some(flag) = iter.as_slice().first() {
+                    if flag != "-o" && flag != "--output" {
+                        return Err(format!("Unexpected argument `{flag}`"));
+                    }
+                    iter.next();
+                    let Some(out) = iter.next() else {
+                        return Err(format!("`{flag}` expects a file"));
+                    };
+                    output = Some(PathBuf::from(out));
+                } else if file == "-" {
+                    return Err(String::from("Use `-o <file>` when building from stdin"));
+                }
+                cli.command = Command::Build(parse_input(file), output);
+                break;
+            }
+            "repl" => {
+                cli.command = Command::Repl;
+                break;
+            }
+            "-" => {
+                cli.command = Command::Run(Input::Stdin);
+                break;
+            }
+            flag if flag.starts_with('-') => return Err(format!("Unknown option `{flag}`")),
+            file => {
+                cli.command = Command::Run(parse_input(file));
+                break;
+            }
+        }
+    }
+
+    // anything after the input belongs to the script
+    let mut rest = iter.as_slice();
+    if rest.first().is_some_and(|x| x == "--") {
+        rest = &rest[1..];
+    }
+    cli.script_args = rest.to_vec();
+    if matches!(
+        cli.command,
+        Command::Check(_) | Command::Disasm(_) | Command::Build(_, _) | Command::Repl
+    ) && !cli.script_args.is_empty()
+    {
+        return Err(format!("Unexpected argument `{}`", cli.script_args[0]));
+    }
+
+    Ok(cli)
+}
@@ -0,0 +1,139 @@
+use crate::methods::METHODS;
+use crate::{Data, Function, Instr};
+use std::fmt::Write;
+
+pub fn mnemonic(instr: &Instr) -> &'static str {
+    match instr {
+        Instr::Null => "NULL",
+        Instr::Print(_) => "PRINT",
+        Instr::Jmp(_, _) => "JMP",
+        Instr::Cmp(_, _) => "CMP",
+        Instr::Mov(_, _) => "MOV",
+        Instr::Add(_, _, _) => "ADD",
+        Instr::Mul(_, _, _) => "MUL",
+        Instr::Sub(_, _, _) => "SUB",
+        Instr::Div(_, _, _) => "DIV",
+        Instr::Mod(_, _, _) => "MOD",
+        Instr::Pow(_, _, _) => "POW",
+        Instr::Eq(_, _, _) => "EQ",
+        Instr::NotEq(_, _, _) => "NOTEQ",
+        Instr::Sup(_, _, _) => "SUP",
+        Instr::SupEq(_, _, _) => "SUPEQ",
+        Instr::Inf(_, _, _) => "INF",
+        Instr::InfEq(_, _, _) => "INFEQ",
+        Instr::BoolAnd(_, _, _) => "BOOLAND",
+        Instr::BoolOr(_, _, _) => "BOOLOR",
+        Instr::ArgCount(_) => "ARGCOUNT",
+        Instr::Arg(_, _) => "ARG",
+        Instr::Env(_, _) => "ENV",
+        Instr::SetEnv(_, _) => "SETENV",
+        Instr::Exit(_) => "EXIT",
+        Instr::ForRange(_, _, _) => "FORRANGE",
+        Instr::ForIn(_, _, _) => "FORIN",
+        Instr::Call(_, _, _) => "CALL",
+        Instr::Ret(_) => "RET",
+        Instr::Neg(_, _) => "NEG",
+        Instr::Not(_, _) => "NOT",
+        Instr::CallMethod(_, _, _) => "CALLMETHOD",
+        Instr::OpenFile(_, _) => "OPENFILE",
+        Instr::Input(_, _) => "INPUT",
+        Instr::NewArray(_, _, _) => "NEWARRAY",
+        Instr::Index(_, _, _) => "INDEX",
+        Instr::Slice(_, _, _) => "SLICE",
+        Instr::SetIndex(_, _, _) => "SETINDEX",
+        Instr::Range(_, _) => "RANGE",
+    }
+}
+
+/// Formats a register, annotated with its variable name or its constant value
+fn register(id: u16, consts: &[Data], variables: &[(String, u16)]) -> String {
+    if let Some((name, _)) = variables.iter().rev().find(|(_, x)| *x == id) {
+        return format!("{id} ({name})");
+    }
+    match consts.get(id as usize) {
+        Some(Data::String(str)) => format!("{id} (={:?})", str.as_str()),
+        Some(Data::Null) | None => id.to_string(),
+        Some(value) => format!("{id} (={value})"),
+    }
+}
+
+/// Lists the instructions, one numbered line each (numbering starts at 1, like jump targets)
+pub fn disassemble(
+    instructions: &[Instr],
+    consts: &[Data],
+    variables: &[(String, u16)],
+    functions: &[Function],
+) -> String {
+    let width = instructions.len().to_string().len();
+    let reg = |id: u16| register(id, consts, variables);
+    let mut out = String::new();
+    for (i, instr) in instructions.iter().enumerate() {
+        for func in functions.iter().filter(|x| x.start == i) {
+            writeln!(
+                out,
+                "{}({} params, registers {}..{}):",
+                func.name, func.params, func.registers.start, func.registers.end
+            )
+            .unwrap();
+        }
+        let operands = match *instr {
+            Instr::Null => String::new(),
+            Instr::Print(x) | Instr::ArgCount(x) | Instr::Exit(x) | Instr::Ret(x) => reg(x),
+            Instr::NewArray(base, len, dest) => format!("{base} {len} {}", reg(dest)),
+            Instr::CallMethod(base, method, dest) => {
+                let name = METHODS.get(method as usize).map_or("?", |x| x.0);
+                format!("{} {name} {}", reg(base), reg(dest))
+            }
+            Instr::Call(func, args, dest) => {
+                let name = functions
+                    .get(func as usize)
+                    .map_or("?", |x| x.name.as_str());
+                format!("{name} {args} {}", reg(dest))
+            }
+            Instr::Jmp(size, is_neg) => {
+                let (offset, target) = if is_neg {
+                    (format!("-{size}"), i as isize - size as isize)
+                } else {
+                    (format!("+{size}"), (i + size as usize) as isize)
+                };
+                format!("{offset} -> {}", target + 1)
+            }
+            Instr::Cmp(cond, size) => {
+                format!("{} +{size} -> {}", reg(cond), i + size as usize + 1)
+            }
+            Instr::ForRange(x, y, size) | Instr::ForIn(x, y, size) => {
+                format!("{} {} +{size} -> {}", reg(x), reg(y), i + size as usize + 1)
+            }
+            Instr::Mov(x, y)
+            | Instr::Arg(x, y)
+            | Instr::Env(x, y)
+            | Instr::SetEnv(x, y)
+            | Instr::Neg(x, y)
+            | Instr::Not(x, y)
+            | Instr::OpenFile(x, y)
+            | Instr::Input(x, y)
+            | Instr::Range(x, y) => {
+                format!("{} {}", reg(x), reg(y))
+            }
+            Instr::Add(x, y, z)
+            | Instr::Mul(x, y, z)
+            | Instr::Sub(x, y, z)
+            | Instr::Div(x, y, z)
+            | Instr::Mod(x, y, z)
+            | Instr::Pow(x, y, z)
+            | Instr::Eq(x, y, z)
+            | Instr::NotEq(x, y, z)
+            | Instr::Sup(x, y, z)
+            | Instr::SupEq(x, y, z)
+            | Instr::Inf(x, y, z)
+            | Instr::InfEq(x, y, z)
+            | Instr::BoolAnd(x, y, z)
+            | Instr::BoolOr(x, y, z)
+            | Instr::Index(x, y, z)
+            | Instr::Slice(x, y, z)
+            | Instr::SetIndex(x, y, z) => format!("{} {} {}", reg(x), reg(y), reg(z)),
+        };
+        writeln!(out, "{:>width$} {:<10} {operands}", i + 1, mnemonic(instr)).unwrap();
+    }
+    out
+}
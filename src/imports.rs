@@ -0,0 +1,293 @@
+use crate::span::SourceFile;
+use crate::{cache, parse, preprocess, syntax_errors, Error, Expr, ExprKind};
+use colored::Colorize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A source file, ready to be compiled once the files it imports are
+pub struct Module {
+    // namespace of the file (its name without the extension), empty for the main file
+    pub name: String,
+    pub code: Vec<Expr>,
+    // indices of the imported modules
+    pub imports: Vec<usize>,
+    pub source: SourceFile,
+    // canonical path and hash of the contents of imported files, to invalidate the cache
+    pub file: Option<(PathBuf, String)>,
+}
+
+struct Loader {
+    modules: Vec<Module>,
+    // canonical and displayed path of the files being loaded, each one importing the next
+    chain: Vec<(PathBuf, String)>,
+}
+
+impl Loader {
+    /// Loads the files imported by `code`, paths being relative to `dir`, and returns their index
+    fn imports(&mut self, code: &[Expr], dir: &Path) -> Result<Vec<usize>, Error> {
+        code.iter()
+            .filter_map(|x| match &x.kind {
+                ExprKind::Import(path) => Some(self.load(&dir.join(path))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn load(&mut self, path: &Path) -> Result<usize, Error> {
+        let display = path.display().to_string();
+        let Ok(canonical) = fs::canonicalize(path) else {
+            fail!(format_args!(
+                "Unable to read imported file '{}'",
+                display.red()
+            ));
+        };
+        if let Some(start) = self.chain.iter().position(|(x, _)| *x == canonical) {
+            let chain: Vec<&str> = self.chain[start..]
+                .iter()
+                .map(|(_, x)| x.as_str())
+                .chain([display.as_str()])
+                .collect();
+            fail!(format_args!("Cyclic import: {}", chain.join(" -> ").red()));
+        }
+        // files imported several times are only parsed once
+        if let Some(i) = self
+            .modules
+            .iter()
+            .position(|x| x.file.as_ref().is_some_and(|(x, _)| *x == canonical))
+        {
+            return Ok(i);
+        }
+
+        let name = path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        if !preprocess::is_identifier(&name) {
+            fail!(
+                format_args!(
+                    "Cannot import '{}', its name isn't a valid identifier",
+                    display.red()
+                ),
+                "Its name is used to call its functions (`name::function()`), rename it"
+            );
+        }
+        if name == "io" || name == "math" {
+            fail!(format_args!(
+                "Cannot import '{}', {name} is a built-in namespace",
+                display.red()
+            ));
+        }
+        let Ok(bytes) = fs::read(&canonical) else {
+            fail!(format_args!(
+                "Unable to read imported file '{}'",
+                display.red()
+            ));
+        };
+        let hash = cache::hash(&bytes);
+        let Ok(src) = String::from_utf8(bytes) else {
+            fail!(format_args!("'{}' is not valid UTF-8", display.red()));
+        };
+        let source = SourceFile::new(display.clone(), &src);
+        let code = parse(&src).map_err(|e| syntax_errors(&e, &source))?;
+
+        self.chain.push((canonical.clone(), display.clone()));
+        let imports = self.imports(&code, path.parent().unwrap_or(Path::new("")));
+        self.chain.pop();
+        let imports = imports?;
+        if self.modules.iter().any(|x| x.name == name) {
+            fail!(format_args!(
+                "Cannot import '{}', another imported file is named {name}",
+                display.red()
+            ));
+        }
+        self.modules.push(Module {
+            name,
+            code: code.into_vec(),
+            imports,
+            source,
+            file: Some((canonical, hash)),
+        });
+        Ok(self.modules.len() - 1)
+    }
+}
+
+/// Loads every file imported (directly or not) by `code`, which comes from `source` and the file at
+/// `path` (if any). Files come after the files they import, so the main file is the last module.
+pub fn load(
+    code: Box<[Expr]>,
+    source: SourceFile,
+    path: Option<&Path>,
+) -> Result<Vec<Module>, Error> {
+    let mut loader = Loader {
+        modules: Vec::new(),
+        chain: Vec::new(),
+    };
+    // a file importing the main file is a cycle too
+    if let Some(canonical) = path.and_then(|x| fs::canonicalize(x).ok()) {
+        loader
+            .chain
+            .push((canonical, path.unwrap().display().to_string()));
+    }
+    let dir = path.and_then(Path::parent).unwrap_or(Path::new(""));
+    let imports = loader.imports(&code, dir)?;
+    loader.modules.push(Module {
+        name: String::new(),
+        code: code.into_vec(),
+        imports,
+        source,
+        file: None,
+    });
+    Ok(loader.modules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arrays::Heap;
+    use crate::cli::Input;
+    use crate::limits::Limits;
+    use crate::{compile, execute, Data};
+
+    // writes `files` in a new directory, returns its path
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("spock-{test}-{}", std::process::id()));
+        for (name, contents) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    fn load_file(path: &Path) -> Result<Vec<Module>, Error> {
+        let src = fs::read_to_string(path).unwrap();
+        let source = SourceFile::new(path.display().to_string(), &src);
+        load(parse(&src).unwrap(), source, Some(path))
+    }
+
+    fn error(path: &Path) -> String {
+        load_file(path).err().unwrap().message
+    }
+
+    #[test]
+    fn namespaced_names() {
+        let dir = write_files(
+            "names",
+            &[
+                (
+                    "main.spock",
+                    "import sub/lib.spock;\nlet a = lib::double(3);\nlet b = double(4);\n\
+                     let c = lib::answer;\nlet d = answer + lib::two();",
+                ),
+                (
+                    "sub/lib.spock",
+                    "import helper.spock;\nlet answer = 42;\nfunc double(x) { return x * 2; }\n\
+                     func two() { return one() + helper::one(); }",
+                ),
+                ("sub/helper.spock", "func one() { return 1; }"),
+            ],
+        );
+        let main = dir.join("main.spock");
+        let names: Vec<String> = load_file(&main)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.name)
+            .collect();
+        // imported files come first
+        assert_eq!(names, ["helper", "lib", ""]);
+
+        let (program, files) = compile(&fs::read_to_string(&main).unwrap(), &Input::File(main));
+        assert_eq!(files.len(), 2);
+        let mut consts = program.consts.clone();
+        execute(
+            &program.instructions,
+            0,
+            &mut consts,
+            &mut Heap::default(),
+            &program.functions,
+            &program.debug,
+            &Limits::default(),
+        )
+        .unwrap();
+        let value = |name: &str| {
+            let (_, id) = program.variables.iter().find(|(x, _)| x == name).unwrap();
+            consts[*id as usize]
+        };
+        assert_eq!(value("a"), Data::Number(6.0));
+        assert_eq!(value("b"), Data::Number(8.0));
+        assert_eq!(value("c"), Data::Number(42.0));
+        assert_eq!(value("d"), Data::Number(44.0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_are_loaded_once() {
+        let dir = write_files(
+            "once",
+            &[
+                ("main.spock", "import a.spock;\nimport b.spock;"),
+                ("a.spock", "import c.spock;"),
+                ("b.spock", "import ./c.spock;"),
+                ("c.spock", "let c = 1;"),
+            ],
+        );
+        let modules = load_file(&dir.join("main.spock")).unwrap();
+        assert_eq!(modules.len(), 4);
+        assert_eq!(modules[1].imports, modules[2].imports);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cyclic_imports() {
+        let dir = write_files(
+            "cycle",
+            &[
+                ("main.spock", "import a.spock;"),
+                ("a.spock", "import b.spock;"),
+                ("b.spock", "import ./a.spock;"),
+                ("self.spock", "import self.spock;"),
+            ],
+        );
+        let chain = |names: &[&str]| {
+            names
+                .iter()
+                .map(|x| dir.join(x).display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        };
+        let cycle = error(&dir.join("main.spock"));
+        assert!(cycle.starts_with("Cyclic import: "));
+        assert!(cycle.contains(&chain(&["a.spock", "b.spock", "./a.spock"])));
+        // the main file is part of the chain too
+        let cycle = error(&dir.join("self.spock"));
+        assert!(cycle.contains(&chain(&["self.spock", "self.spock"])));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_imports() {
+        let dir = write_files(
+            "invalid",
+            &[
+                ("missing.spock", "import nope.spock;"),
+                ("syntax.spock", "import bad.spock;"),
+                ("bad.spock", "let = 1;"),
+                ("builtin.spock", "import math.spock;"),
+                ("math.spock", ""),
+                ("name.spock", "import my-lib.spock;"),
+                ("my-lib.spock", ""),
+                ("twice.spock", "import a/c.spock;\nimport b/c.spock;"),
+                ("a/c.spock", ""),
+                ("b/c.spock", ""),
+            ],
+        );
+        let error = |name: &str| error(&dir.join(name));
+        assert!(error("missing.spock").starts_with("Unable to read imported file"));
+        assert!(error("syntax.spock").starts_with("Syntax error: Unexpected `=` at "));
+        assert!(error("builtin.spock").ends_with("math is a built-in namespace"));
+        assert!(error("name.spock").ends_with("its name isn't a valid identifier"));
+        assert!(error("twice.spock").ends_with("another imported file is named c"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
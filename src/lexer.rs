@@ -0,0 +1,466 @@
+//! Tokenizer feeding the grammar, whose tokens carry their byte span in the code
+
+use crate::literals::{parse_number, unescape, SourceError};
+use crate::preprocess::{is_identifier_start, string_end};
+use crate::ExprKind;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tok {
+    Ident(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    // path following `import`
+    Path(String),
+    // keywords
+    Let,
+    If,
+    Else,
+    While,
+    For,
+    In,
+    Func,
+    Return,
+    Break,
+    Continue,
+    True,
+    False,
+    Import,
+    // punctuation
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Comma,
+    Semicolon,
+    Colon,
+    DoubleColon,
+    Dot,
+    // operators
+    Assign,
+    Eq,
+    NotEq,
+    Inf,
+    InfEq,
+    Sup,
+    SupEq,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Caret,
+    Not,
+    And,
+    Or,
+    PlusAssign,
+    MinusAssign,
+    StarAssign,
+    SlashAssign,
+    PercentAssign,
+    CaretAssign,
+}
+
+const KEYWORDS: &[(&str, Tok)] = &[
+    ("let", Tok::Let),
+    ("if", Tok::If),
+    ("else", Tok::Else),
+    ("while", Tok::While),
+    ("for", Tok::For),
+    ("in", Tok::In),
+    ("func", Tok::Func),
+    ("return", Tok::Return),
+    ("break", Tok::Break),
+    ("continue", Tok::Continue),
+    ("true", Tok::True),
+    ("false", Tok::False),
+    ("import", Tok::Import),
+];
+
+// longest first, so that `+=` isn't read as `+` then `=`
+const SYMBOLS: &[(&str, Tok)] = &[
+    ("::", Tok::DoubleColon),
+    ("==", Tok::Eq),
+    ("!=", Tok::NotEq),
+    ("<=", Tok::InfEq),
+    (">=", Tok::SupEq),
+    ("&&", Tok::And),
+    ("||", Tok::Or),
+    ("+=", Tok::PlusAssign),
+    ("-=", Tok::MinusAssign),
+    ("*=", Tok::StarAssign),
+    ("/=", Tok::SlashAssign),
+    ("%=", Tok::PercentAssign),
+    ("^=", Tok::CaretAssign),
+    ("(", Tok::LParen),
+    (")", Tok::RParen),
+    ("{", Tok::LBrace),
+    ("}", Tok::RBrace),
+    ("[", Tok::LBracket),
+    ("]", Tok::RBracket),
+    (",", Tok::Comma),
+    (";", Tok::Semicolon),
+    (":", Tok::Colon),
+    (".", Tok::Dot),
+    ("=", Tok::Assign),
+    ("<", Tok::Inf),
+    (">", Tok::Sup),
+    ("+", Tok::Plus),
+    ("-", Tok::Minus),
+    ("*", Tok::Star),
+    ("/", Tok::Slash),
+    ("%", Tok::Percent),
+    ("^", Tok::Caret),
+    ("!", Tok::Not),
+];
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tok::Ident(x) | Tok::Path(x) => write!(f, "{x}"),
+            Tok::Int(x) => write!(f, "{x}"),
+            Tok::Float(x) => write!(f, "{x:?}"),
+            Tok::Str(x) => write!(f, "{x:?}"),
+            other => {
+                let (text, _) = KEYWORDS
+                    .iter()
+                    .chain(SYMBOLS)
+                    .find(|(_, x)| x == other)
+                    .unwrap();
+                write!(f, "{text}")
+            }
+        }
+    }
+}
+
+pub const UNTERMINATED_COMMENT: &str = "Unterminated block comment";
+
+fn is_identifier_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+pub struct Lexer<'a> {
+    code: &'a str,
+    pos: usize,
+    // the next token is the path of an import
+    after_import: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(code: &'a str) -> Self {
+        Lexer {
+            code,
+            pos: 0,
+            after_import: false,
+        }
+    }
+
+    /// Skips the whitespace and the comments (`//` and nestable `/* */`) before the next token
+    fn skip_comments(&mut self) -> Result<(), SourceError> {
+        loop {
+            let rest = &self.code[self.pos..];
+            self.pos += rest.len() - rest.trim_start().len();
+            let rest = &self.code[self.pos..];
+            if rest.starts_with("//") {
+                self.pos += rest.find('\n').unwrap_or(rest.len());
+            } else if rest.starts_with("/*") {
+                self.pos = self.block_comment_end(self.pos)?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Position right after the block comment starting at `start`, comments inside it included
+    fn block_comment_end(&self, start: usize) -> Result<usize, SourceError> {
+        let bytes = self.code.as_bytes();
+        let mut depth = 0;
+        let mut i = start;
+        while i < bytes.len() {
+            if bytes[i..].starts_with(b"/*") {
+                depth += 1;
+                i += 2;
+            } else if bytes[i..].starts_with(b"*/") {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return Ok(i);
+                }
+            } else {
+                i += 1;
+            }
+        }
+        Err(SourceError {
+            location: start,
+            message: String::from(UNTERMINATED_COMMENT),
+        })
+    }
+
+    /// End of the number literal starting at `start`: `1`, `1_000`, `1.5`, `1.`, `.5`, `1e6`,
+    /// `2.5E-3`, `0xFF`, `0o17` or `0b1010`. Letters and digits right after it are part of it, so
+    /// that `0b102` or `1e` are reported as invalid numbers by `parse_number`.
+    fn number_end(&self, start: usize) -> usize {
+        let bytes = self.code.as_bytes();
+        let skip = |mut i: usize, f: fn(u8) -> bool| {
+            while i < bytes.len() && (f(bytes[i]) || bytes[i] == b'_') {
+                i += 1;
+            }
+            i
+        };
+        let prefixed = bytes[start] == b'0'
+            && matches!(
+                bytes.get(start + 1),
+                Some(b'x' | b'X' | b'o' | b'O' | b'b' | b'B')
+            );
+        if prefixed {
+            return skip(start + 2, is_identifier_char);
+        }
+        let mut i = skip(start, |c| c.is_ascii_digit());
+        // in `5.toStr()`, the dot calls a method
+        if bytes.get(i) == Some(&b'.') && !bytes.get(i + 1).is_some_and(|c| is_identifier_start(*c))
+        {
+            i = skip(i + 1, |c| c.is_ascii_digit());
+        }
+        if matches!(bytes.get(i), Some(b'e' | b'E')) {
+            let sign = usize::from(matches!(bytes.get(i + 1), Some(b'+' | b'-')));
+            if bytes.get(i + 1 + sign).is_some_and(u8::is_ascii_digit) {
+                i = skip(i + 1 + sign, |c| c.is_ascii_digit());
+            }
+        }
+        skip(i, is_identifier_char)
+    }
+
+    /// Reads the string literal whose opening quote is at `start`, `raw` if it is preceded by `r`
+    fn string(&mut self, start: usize, raw: bool) -> Result<Tok, SourceError> {
+        let bytes = self.code.as_bytes();
+        let Some(end) = string_end(bytes, start) else {
+            return Err(SourceError {
+                location: start,
+                message: String::from("Unterminated string"),
+            });
+        };
+        self.pos = end;
+        let quotes = if bytes[start..].starts_with(b"\"\"\"") {
+            3
+        } else {
+            1
+        };
+        let contents = &self.code[start + quotes..end - quotes];
+        if raw {
+            // escape sequences are left as is
+            return Ok(Tok::Str(contents.to_string()));
+        }
+        unescape(contents, start + quotes).map(Tok::Str)
+    }
+
+    fn next_token(&mut self, start: usize) -> Result<Tok, SourceError> {
+        let bytes = self.code.as_bytes();
+        let rest = &self.code[start..];
+        let c = bytes[start];
+        if std::mem::take(&mut self.after_import) {
+            let len = rest
+                .find(|c: char| c.is_whitespace() || c == ';')
+                .unwrap_or(rest.len());
+            self.pos = start + len;
+            return Ok(Tok::Path(rest[..len].to_string()));
+        }
+        if c == b'"' {
+            return self.string(start, false);
+        }
+        if c == b'r' && bytes.get(start + 1) == Some(&b'"') {
+            return self.string(start + 1, true);
+        }
+        if c.is_ascii_digit() || (c == b'.' && bytes.get(start + 1).is_some_and(u8::is_ascii_digit))
+        {
+            self.pos = self.number_end(start);
+            return match parse_number(&self.code[start..self.pos], start)? {
+                ExprKind::Int(x) => Ok(Tok::Int(x)),
+                ExprKind::Num(x) => Ok(Tok::Float(x)),
+                _ => unreachable!(),
+            };
+        }
+        if is_identifier_start(c) {
+            let len = bytes[start..]
+                .iter()
+                .position(|&c| !is_identifier_char(c))
+                .unwrap_or(rest.len());
+            self.pos = start + len;
+            let name = &rest[..len];
+            if let Some((_, keyword)) = KEYWORDS.iter().find(|(x, _)| *x == name) {
+                self.after_import = *keyword == Tok::Import;
+                return Ok(keyword.clone());
+            }
+            return Ok(Tok::Ident(name.to_string()));
+        }
+        if let Some((text, symbol)) = SYMBOLS.iter().find(|(x, _)| rest.starts_with(x)) {
+            self.pos = start + text.len();
+            return Ok(symbol.clone());
+        }
+        let c = rest.chars().next().unwrap();
+        Err(SourceError {
+            location: start,
+            message: format!("Unexpected character `{c}`"),
+        })
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Result<(usize, Tok, usize), SourceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos == self.code.len() {
+            return None;
+        }
+        // stops at the first invalid token
+        if let Err(e) = self.skip_comments() {
+            self.pos = self.code.len();
+            return Some(Err(e));
+        }
+        let start = self.pos;
+        if start == self.code.len() {
+            return None;
+        }
+        let token = self.next_token(start);
+        if token.is_err() {
+            self.pos = self.code.len();
+        }
+        Some(token.map(|x| (start, x, self.pos)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(code: &str) -> Vec<Tok> {
+        Lexer::new(code).map(|x| x.unwrap().1).collect()
+    }
+
+    fn error(code: &str) -> SourceError {
+        Lexer::new(code).find_map(Result::err).unwrap()
+    }
+
+    #[test]
+    fn spans() {
+        let spans: Vec<_> = Lexer::new("let x = 10;")
+            .map(|x| x.map(|(start, _, end)| (start, end)))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(spans, [(0, 3), (4, 5), (6, 7), (8, 10), (10, 11)]);
+    }
+
+    #[test]
+    fn keywords_and_symbols() {
+        assert_eq!(
+            tokens("if iffy x += 1 == y::z"),
+            [
+                Tok::If,
+                Tok::Ident(String::from("iffy")),
+                Tok::Ident(String::from("x")),
+                Tok::PlusAssign,
+                Tok::Int(1),
+                Tok::Eq,
+                Tok::Ident(String::from("y")),
+                Tok::DoubleColon,
+                Tok::Ident(String::from("z")),
+            ]
+        );
+        assert_eq!(tokens("a.b"), tokens("a . b"));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(
+            tokens("1_000 0xFF 0b101 .5 2.5e-1 1e6"),
+            [
+                Tok::Int(1000),
+                Tok::Int(255),
+                Tok::Int(5),
+                Tok::Float(0.5),
+                Tok::Float(0.25),
+                Tok::Float(1e6),
+            ]
+        );
+        // methods can be called on numbers
+        assert_eq!(
+            tokens("5.len 1. 2.5.x"),
+            [
+                Tok::Int(5),
+                Tok::Dot,
+                Tok::Ident(String::from("len")),
+                Tok::Float(1.0),
+                Tok::Float(2.5),
+                Tok::Dot,
+                Tok::Ident(String::from("x")),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_numbers() {
+        for number in ["0x", "0b102", "1e", "12ab", "1_", "1__0", "0x_F", "1_.5"] {
+            let e = error(&format!("x = {number};"));
+            assert_eq!(e.location, 4);
+            assert_eq!(e.message, format!("Invalid number `{number}`"), "{number}");
+        }
+        assert_eq!(tokens("0xF_F 1_000.5"), [Tok::Int(255), Tok::Float(1000.5)]);
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            tokens(r#""a\tb" r"a\tb" """say "hi" twice""""#),
+            [
+                Tok::Str(String::from("a\tb")),
+                Tok::Str(String::from(r"a\tb")),
+                Tok::Str(String::from(r#"say "hi" twice"#)),
+            ]
+        );
+        assert_eq!(error("let x = \"abc;").location, 8);
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(
+            tokens("// line\n1 /* block /* nested */ still */ 2 // end"),
+            [Tok::Int(1), Tok::Int(2)]
+        );
+        // a division isn't a comment
+        assert_eq!(tokens("1 / 2"), [Tok::Int(1), Tok::Slash, Tok::Int(2)]);
+    }
+
+    #[test]
+    fn unterminated_comment() {
+        let code = "1 /* a /* b */ 2";
+        let e = error(code);
+        assert_eq!(e.message, UNTERMINATED_COMMENT);
+        assert_eq!(e.location, code.find("/*").unwrap());
+        // the lexer stops after an error
+        let mut lexer = Lexer::new(code);
+        assert!(lexer.next().unwrap().is_ok());
+        assert!(lexer.next().unwrap().is_err());
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn import_path() {
+        assert_eq!(
+            tokens("import ../lib/math.spock;"),
+            [
+                Tok::Import,
+                Tok::Path(String::from("../lib/math.spock")),
+                Tok::Semicolon,
+            ]
+        );
+    }
+
+    #[test]
+    fn unexpected_character() {
+        let e = error("let x = 1 $ 2;");
+        assert_eq!(e.location, 10);
+        assert_eq!(e.message, "Unexpected character `$`");
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Exit status used when a script is stopped for exceeding one of its limits
+pub const EXIT_LIMIT: i32 = 3;
+
+/// Resources a script is allowed to use, everything but the call depth is unlimited by default
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_instructions: u64,
+    pub timeout: Option<Duration>,
+    // size of the register file (`consts`)
+    pub max_registers: usize,
+    // total size of the strings created while running
+    pub max_string_bytes: usize,
+    // total number of elements of the arrays created while running
+    pub max_array_elements: usize,
+    // number of nested function calls, catches runaway recursion
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_instructions: u64::MAX,
+            timeout: None,
+            max_registers: usize::MAX,
+            max_string_bytes: usize::MAX,
+            max_array_elements: usize::MAX,
+            max_depth: 10_000,
+        }
+    }
+}
+
+/// How often (in instructions) the timeout is checked
+pub const TIMEOUT_CHECK_INTERVAL: u64 = 4096;
+
+/// A script went over one of its limits, `main` exits with `EXIT_LIMIT` when it happens
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitExceeded(pub String);
+
+impl LimitExceeded {
+    pub fn report(&self) {
+        eprintln!(
+            "--------------\n\u{001b}[31mSPOCK ERROR:\u{001b}[0m\n{}\n\u{001b}[34mPOSSIBLE SOLUTION:\u{001b}[0m\nRaise the limit with the matching command-line option\n--------------",
+            self.0
+        );
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Resources used so far by a running script
+pub struct Usage {
+    pub instructions: u64,
+    // instruction count at which the limits are checked next, so that running an instruction
+    // only costs one comparison
+    next_check: u64,
+    pub string_bytes: usize,
+    pub array_elements: usize,
+    pub deadline: Option<Instant>,
+}
+
+impl Usage {
+    pub fn new(limits: &Limits) -> Self {
+        let mut usage = Usage {
+            instructions: 0,
+            next_check: 0,
+            string_bytes: 0,
+            array_elements: 0,
+            deadline: limits.timeout.map(|x| Instant::now() + x),
+        };
+        usage.schedule_check(limits);
+        usage
+    }
+
+    fn schedule_check(&mut self, limits: &Limits) {
+        self.next_check = limits.max_instructions.saturating_add(1);
+        if self.deadline.is_some() {
+            self.next_check = self
+                .next_check
+                .min(self.instructions + TIMEOUT_CHECK_INTERVAL);
+        }
+    }
+
+    #[inline(always)]
+    pub fn count_instruction(&mut self, limits: &Limits) -> Result<(), LimitExceeded> {
+        self.instructions += 1;
+        if self.instructions >= self.next_check {
+            return self.check(limits);
+        }
+        Ok(())
+    }
+
+    #[cold]
+    fn check(&mut self, limits: &Limits) -> Result<(), LimitExceeded> {
+        if self.instructions > limits.max_instructions {
+            return Err(LimitExceeded(format!(
+                "Instruction limit exceeded ({} instructions)",
+                limits.max_instructions
+            )));
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() > deadline {
+                return Err(LimitExceeded(format!(
+                    "Timeout exceeded ({:.2?})",
+                    limits.timeout.unwrap()
+                )));
+            }
+        }
+        self.schedule_check(limits);
+        Ok(())
+    }
+
+    pub fn count_string(&mut self, len: usize, limits: &Limits) -> Result<(), LimitExceeded> {
+        self.string_bytes += len;
+        if self.string_bytes > limits.max_string_bytes {
+            return Err(LimitExceeded(format!(
+                "String memory limit exceeded ({} bytes)",
+                limits.max_string_bytes
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn count_elements(&mut self, len: usize, limits: &Limits) -> Result<(), LimitExceeded> {
+        self.array_elements = self.array_elements.saturating_add(len);
+        if self.array_elements > limits.max_array_elements {
+            return Err(LimitExceeded(format!(
+                "Array memory limit exceeded ({} elements)",
+                limits.max_array_elements
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub fn check_registers(registers: usize, limits: &Limits) -> Result<(), LimitExceeded> {
+    if registers > limits.max_registers {
+        return Err(LimitExceeded(format!(
+            "Register limit exceeded ({registers} registers, the limit is {})",
+            limits.max_registers
+        )));
+    }
+    Ok(())
+}
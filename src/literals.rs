@@ -0,0 +1,116 @@
+//! Decoding of the literals written in the source code
+
+use crate::ExprKind;
+use std::fmt;
+use std::num::IntErrorKind;
+
+/// Error found in the source code outside of the grammar (invalid literal, macro,...), `location`
+/// is a byte offset in the source code
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceError {
+    pub location: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.location)
+    }
+}
+
+/// Decodes the escape sequences (`\n`, `\t`, `\r`, `\0`, `\\`, `\"`, `\'` and `\u{...}`) of a string
+/// literal whose contents start at byte `offset` of the source code
+pub fn unescape(src: &str, offset: usize) -> Result<String, SourceError> {
+    let mut out = String::with_capacity(src.len());
+    let mut chars = src.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        let error = |message: String| SourceError {
+            location: offset + i,
+            message,
+        };
+        match chars.next() {
+            Some((_, 'n')) => out.push('\n'),
+            Some((_, 't')) => out.push('\t'),
+            Some((_, 'r')) => out.push('\r'),
+            Some((_, '0')) => out.push('\0'),
+            Some((_, '\\')) => out.push('\\'),
+            Some((_, '"')) => out.push('"'),
+            Some((_, '\'')) => out.push('\''),
+            Some((_, 'u')) => {
+                let Some((hex, _)) = src[i + 2..]
+                    .strip_prefix('{')
+                    .and_then(|x| x.split_once('}'))
+                else {
+                    return Err(error(String::from(
+                        "Invalid unicode escape, expected `\\u{...}`",
+                    )));
+                };
+                let code = Some(hex)
+                    .filter(|x| (1..=6).contains(&x.len()))
+                    .filter(|x| x.chars().all(|c| c.is_ascii_hexdigit()))
+                    .and_then(|x| u32::from_str_radix(x, 16).ok())
+                    .and_then(char::from_u32);
+                let Some(code) = code else {
+                    return Err(error(format!("Invalid unicode character `\\u{{{hex}}}`")));
+                };
+                out.push(code);
+                // skip the braces and the digits
+                chars.nth(hex.len() + 1);
+            }
+            Some((_, other)) => {
+                return Err(error(format!("Invalid escape sequence `\\{other}`")));
+            }
+            None => return Err(error(String::from("Unterminated escape sequence"))),
+        }
+    }
+    Ok(out)
+}
+
+/// Integers are stored as `f64` when running, so they must fit in its mantissa
+const MAX_INT: i64 = 1 << f64::MANTISSA_DIGITS;
+
+/// Parses a number literal starting at byte `offset` of the source code, keeping integers apart
+/// from floats
+pub fn parse_number(src: &str, offset: usize) -> Result<ExprKind, SourceError> {
+    let error = |message: String| SourceError {
+        location: offset,
+        message,
+    };
+    let (radix, prefix) = match src.get(..2) {
+        Some("0x" | "0X") => (16, 2),
+        Some("0o" | "0O") => (8, 2),
+        Some("0b" | "0B") => (2, 2),
+        _ => (10, 0),
+    };
+    // separators can only be between two digits
+    let bytes = src.as_bytes();
+    let is_digit = |i: usize| i >= prefix && (bytes[i] as char).is_digit(radix);
+    let misplaced = bytes.iter().enumerate().any(|(i, c)| {
+        *c == b'_' && (i == 0 || i + 1 == bytes.len() || !is_digit(i - 1) || !is_digit(i + 1))
+    });
+    if misplaced {
+        return Err(error(format!("Invalid number `{src}`")));
+    }
+    let digits = src[prefix..].replace('_', "");
+    let digits = digits.as_str();
+    if radix == 10 && digits.contains(['.', 'e', 'E']) {
+        return match digits.parse::<f64>() {
+            Ok(num) if num.is_finite() => Ok(ExprKind::Num(num)),
+            Ok(_) => Err(error(format!("Number `{src}` is too large"))),
+            Err(_) => Err(error(format!("Invalid number `{src}`"))),
+        };
+    }
+    match i64::from_str_radix(digits, radix) {
+        Ok(num) if num <= MAX_INT => Ok(ExprKind::Int(num)),
+        Err(e) if *e.kind() != IntErrorKind::PosOverflow => {
+            Err(error(format!("Invalid number `{src}`")))
+        }
+        _ => Err(error(format!(
+            "Integer `{src}` is too large (the maximum is {MAX_INT})"
+        ))),
+    }
+}
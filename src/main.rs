@@ -1,21 +1,66 @@
 use colored::Colorize;
 use concat_string::concat_string;
 use internment::Intern;
-use lalrpop_util::lalrpop_mod;
+use lalrpop_util::{lalrpop_mod, ParseError};
+use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
+use std::fmt;
 use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
 use std::time::Instant;
 
-#[derive(Debug, Clone, PartialEq, Copy)]
+// arguments given after the script's name on the command line
+static SCRIPT_ARGS: OnceLock<Box<[String]>> = OnceLock::new();
+// whether `log!` prints anything, set by `--log`
+static LOG: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum Data {
     Number(f64),
     Bool(bool),
     String(Intern<String>),
     Null,
+    // index of an array of the VM's heap, only created while running, so never part of a
+    // compiled program
+    #[serde(skip)]
+    Array(u32),
+    // path of a file opened with `io::open`, only created while running too
+    #[serde(skip)]
+    File(Intern<String>),
+}
+
+impl Data {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Data::Number(_) => "Number",
+            Data::Bool(_) => "Boolean",
+            Data::String(_) => "String",
+            Data::Null => "Null",
+            Data::Array(_) => "Array",
+            Data::File(_) => "File",
+        }
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Data::Number(num) => write!(f, "{num}"),
+            Data::Bool(bool) => write!(f, "{bool}"),
+            Data::String(str) => write!(f, "{str}"),
+            Data::Null => write!(f, "null"),
+            // the elements are only known by the heap, see `Heap::show`
+            Data::Array(_) => write!(f, "[...]"),
+            Data::File(path) => write!(f, "<file {path}>"),
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Copy)]
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum Instr {
     Null,
@@ -44,6 +89,176 @@ pub enum Instr {
     InfEq(u16, u16, u16),
     BoolAnd(u16, u16, u16),
     BoolOr(u16, u16, u16),
+
+    // PROCESS
+    // dest
+    ArgCount(u16),
+    // index -- dest
+    Arg(u16, u16),
+    // name -- dest
+    Env(u16, u16),
+    // name -- value
+    SetEnv(u16, u16),
+    // code
+    Exit(u16),
+
+    // LOOPS
+    // first of 3 registers (current, end, step) -- variable -- size of the loop
+    ForRange(u16, u16, u16),
+    // iterable -- first of 2 registers (position, variable) -- size of the loop
+    ForIn(u16, u16, u16),
+
+    // FUNCTIONS
+    // function -- first of the registers holding the arguments -- dest
+    Call(u16, u16, u16),
+    // value
+    Ret(u16),
+
+    // UNARY OPS
+    // operand -- dest
+    Neg(u16, u16),
+    Not(u16, u16),
+
+    // METHODS
+    // receiver, followed by the arguments -- method -- dest
+    CallMethod(u16, u16, u16),
+
+    // IO
+    // path -- dest
+    OpenFile(u16, u16),
+    // prompt (or null) -- dest
+    Input(u16, u16),
+
+    // ARRAYS
+    // first element -- number of elements -- dest
+    NewArray(u16, u16, u16),
+    // array -- index -- dest
+    Index(u16, u16, u16),
+    // array -- first of 2 registers (start, end) -- dest
+    Slice(u16, u16, u16),
+    // array -- index -- value
+    SetIndex(u16, u16, u16),
+    // first of 3 registers (start, end, step) -- dest
+    Range(u16, u16),
+}
+
+/// A user-defined function, its code is part of the program's instructions
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Function {
+    pub name: String,
+    pub params: u16,
+    // index of the first instruction
+    pub start: usize,
+    // registers used by the function (parameters first), saved and restored around each call
+    pub registers: std::ops::Range<u16>,
+}
+
+/// Compilation state that isn't tied to a scope
+#[derive(Debug, Default)]
+pub struct Context {
+    pub functions: Vec<Function>,
+    // whether the code being compiled is the body of a function
+    in_function: bool,
+    // number of loops the code being compiled is in
+    loops: usize,
+    // namespace of the imported file being compiled, empty for the main file
+    module: String,
+    // namespaces of the files it imports
+    imports: Vec<String>,
+    // file being compiled
+    pub source: SourceFile,
+    // span of the code being compiled, for error messages
+    span: Span,
+}
+
+/// Compiled instructions and the span of the code each one comes from
+#[derive(Debug, Default)]
+pub struct Code {
+    pub instrs: Vec<Instr>,
+    pub spans: Vec<Span>,
+    // span given to the instructions pushed
+    span: Span,
+}
+
+impl Code {
+    fn new(span: Span) -> Self {
+        Code {
+            span,
+            ..Code::default()
+        }
+    }
+
+    fn push(&mut self, x: Instr) {
+        self.instrs.push(x);
+        self.spans.push(self.span);
+    }
+
+    fn extend(&mut self, code: Code) {
+        self.instrs.extend(code.instrs);
+        self.spans.extend(code.spans);
+    }
+}
+
+impl std::ops::Deref for Code {
+    type Target = [Instr];
+
+    fn deref(&self) -> &[Instr] {
+        &self.instrs
+    }
+}
+
+impl std::ops::DerefMut for Code {
+    fn deref_mut(&mut self) -> &mut [Instr] {
+        &mut self.instrs
+    }
+}
+
+/// Attributes what is compiled from now on (instructions and errors) to `span`, returns the
+/// previous span to restore it afterwards
+fn enter_span(span: Span, ctx: &mut Context, output: &mut Code) -> Span {
+    output.span = span;
+    std::mem::replace(&mut ctx.span, span)
+}
+
+impl Context {
+    /// ` at file:line:column` of the code being compiled
+    fn at(&self) -> String {
+        concat_string!(" at ", self.source.describe(self.span.start))
+    }
+
+    /// Expression generated by the compiler, attributed to the code being compiled
+    fn expr(&self, kind: ExprKind) -> Expr {
+        Expr::new(kind, self.span)
+    }
+
+    /// Full name of a function declared in the file being compiled
+    fn qualify(&self, name: &str) -> String {
+        if self.module.is_empty() {
+            name.to_string()
+        } else {
+            concat_string!(self.module, "::", name)
+        }
+    }
+
+    /// Finds a function called from the file being compiled: its own functions come first, then
+    /// those of the files it imports, which can also be called as `file::name`
+    fn find_function(&self, name: &str) -> Option<usize> {
+        let candidates: Vec<String> = match name.split_once("::") {
+            Some((module, _)) if self.imports.iter().any(|x| x == module) => vec![name.to_string()],
+            Some(_) => Vec::new(),
+            None => std::iter::once(self.qualify(name))
+                .chain(
+                    self.imports
+                        .iter()
+                        .rev()
+                        .map(|x| concat_string!(x, "::", name)),
+                )
+                .collect(),
+        };
+        candidates
+            .iter()
+            .find_map(|x| self.functions.iter().rposition(|f| &f.name == x))
+    }
 }
 
 macro_rules! error {
@@ -61,10 +276,151 @@ macro_rules! error {
     }
 }
 
-fn execute(instructions: &[Instr], consts: &mut [Data]) {
+/// Debugging output of the compiler and the VM, printed to stderr with `--log`
+macro_rules! log {
+    ($($x:tt)*) => {
+        if $crate::LOG.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!("\x1b[33m[LOG] {}\x1b[0m", format!($($x)*));
+        }
+    }
+}
+
+/// Returns an error from the compiler or the VM, so that the REPL can recover from it. Takes the
+/// same arguments as `error!`.
+macro_rules! fail {
+    ($x: expr) => {
+        return Err($crate::Error::new($x, None).into())
+    };
+    ($x: expr, $y: expr) => {
+        return Err($crate::Error::new($x, Some($y)).into())
+    };
+}
+
+/// An error of the script being compiled or run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub message: String,
+    pub solution: Option<String>,
+}
+
+impl Error {
+    fn new(message: impl fmt::Display, solution: Option<&str>) -> Self {
+        Error {
+            message: message.to_string(),
+            solution: solution.map(String::from),
+        }
+    }
+
+    pub fn report(&self) {
+        match &self.solution {
+            Some(solution) => eprintln!(
+                "--------------\n\u{001b}[31mSPOCK ERROR:\u{001b}[0m\n{}\n\u{001b}[34mPOSSIBLE SOLUTION:\u{001b}[0m\n{solution}\n--------------",
+                self.message
+            ),
+            None => eprintln!(
+                "--------------\n\u{001b}[31mSPOCK ERROR:\u{001b}[0m\n{}\n--------------",
+                self.message
+            ),
+        }
+    }
+
+    /// Reports the error and exits, outside of the REPL
+    pub fn exit(&self) -> ! {
+        self.report();
+        std::process::exit(1);
+    }
+}
+
+/// Why a script stopped before its end
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    Error(Error),
+    Limit(LimitExceeded),
+}
+
+impl RuntimeError {
+    /// Adds the location of the instruction that failed to the message of an error
+    fn at(self, location: String) -> Self {
+        match self {
+            RuntimeError::Error(mut e) => {
+                e.message.push_str(&location);
+                RuntimeError::Error(e)
+            }
+            limit => limit,
+        }
+    }
+
+    pub fn report(&self) {
+        match self {
+            RuntimeError::Error(e) => e.report(),
+            RuntimeError::Limit(e) => e.report(),
+        }
+    }
+}
+
+impl From<Error> for RuntimeError {
+    fn from(e: Error) -> Self {
+        RuntimeError::Error(e)
+    }
+}
+
+impl From<LimitExceeded> for RuntimeError {
+    fn from(e: LimitExceeded) -> Self {
+        RuntimeError::Limit(e)
+    }
+}
+
+mod arrays;
+mod asm;
+mod bytecode;
+mod cache;
+mod cli;
+mod disasm;
+mod imports;
+mod lexer;
+mod limits;
+mod literals;
+mod methods;
+mod preprocess;
+mod repl;
+mod span;
+use arrays::Heap;
+use bytecode::Program;
+use cli::{Command, Input};
+use limits::{LimitExceeded, Limits, Usage};
+use literals::SourceError;
+use span::{DebugInfo, SourceFile, Span};
+
+fn script_args() -> &'static [String] {
+    SCRIPT_ARGS.get().map_or(&[], |x| x)
+}
+
+fn execute(
+    instructions: &[Instr],
+    start: usize,
+    consts: &mut [Data],
+    heap: &mut Heap,
+    functions: &[Function],
+    debug: &DebugInfo,
+    limits: &Limits,
+) -> Result<(), RuntimeError> {
+    limits::check_registers(consts.len(), limits)?;
+    let mut usage = Usage::new(limits);
+    // registers of a function always hold their initial value outside of its calls
+    let initial = if functions.is_empty() {
+        Vec::new()
+    } else {
+        consts.to_vec()
+    };
+    // return address -- dest -- function
+    let mut frames: Vec<(usize, u16, u16)> = Vec::new();
+    // registers of the callers, restored when their callee returns
+    let mut saved: Vec<Data> = Vec::new();
+    let mut call_args: Vec<Data> = Vec::new();
     let len = instructions.len();
-    let mut i: usize = 0;
+    let mut i: usize = start;
     while i < len {
+        usage.count_instruction(limits)?;
         match instructions[i] {
             Instr::Jmp(size, is_neg) => {
                 if is_neg {
@@ -90,12 +446,15 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
                     }
                     (Data::String(parent), Data::String(child)) => {
                         let result = concat_string!(*parent, *child);
+                        usage.count_string(result.len(), limits)?;
                         consts[dest as usize] = Data::String(Intern::from(result));
                     }
                     _ => {
-                        error!(format_args!(
-                            "UNSUPPORTED OPERATION: {:?} + {:?}",
-                            first_elem, second_elem
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: {:?} + {:?}{}",
+                            first_elem,
+                            second_elem,
+                            debug.at(i)
                         ));
                     }
                 }
@@ -109,9 +468,11 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
                         consts[dest as usize] = Data::Number(parent * child);
                     }
                     _ => {
-                        error!(format_args!(
-                            "UNSUPPORTED OPERATION: {:?} * {:?}",
-                            first_elem, second_elem
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: {:?} * {:?}{}",
+                            first_elem,
+                            second_elem,
+                            debug.at(i)
                         ));
                     }
                 }
@@ -125,9 +486,11 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
                         consts[dest as usize] = Data::Number(parent / child);
                     }
                     _ => {
-                        error!(format_args!(
-                            "UNSUPPORTED OPERATION: {:?} / {:?}",
-                            first_elem, second_elem
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: {:?} / {:?}{}",
+                            first_elem,
+                            second_elem,
+                            debug.at(i)
                         ));
                     }
                 }
@@ -141,9 +504,11 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
                         consts[dest as usize] = Data::Number(parent - child);
                     }
                     _ => {
-                        error!(format_args!(
-                            "UNSUPPORTED OPERATION: {:?} - {:?}",
-                            first_elem, second_elem
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: {:?} - {:?}{}",
+                            first_elem,
+                            second_elem,
+                            debug.at(i)
                         ));
                     }
                 }
@@ -157,9 +522,11 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
                         consts[dest as usize] = Data::Number(parent % child);
                     }
                     _ => {
-                        error!(format_args!(
-                            "UNSUPPORTED OPERATION: {:?} % {:?}",
-                            first_elem, second_elem
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: {:?} % {:?}{}",
+                            first_elem,
+                            second_elem,
+                            debug.at(i)
                         ));
                     }
                 }
@@ -173,9 +540,11 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
                         consts[dest as usize] = Data::Number(parent.powf(child));
                     }
                     _ => {
-                        error!(format_args!(
-                            "UNSUPPORTED OPERATION: {:?} ^ {:?}",
-                            first_elem, second_elem
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: {:?} ^ {:?}{}",
+                            first_elem,
+                            second_elem,
+                            debug.at(i)
                         ));
                     }
                 }
@@ -183,12 +552,12 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
             Instr::Eq(o1, o2, dest) => {
                 let first_elem = consts[o1 as usize];
                 let second_elem = consts[o2 as usize];
-                consts[dest as usize] = Data::Bool(first_elem == second_elem);
+                consts[dest as usize] = Data::Bool(heap.equal(first_elem, second_elem));
             }
             Instr::NotEq(o1, o2, dest) => {
                 let first_elem = consts[o1 as usize];
                 let second_elem = consts[o2 as usize];
-                consts[dest as usize] = Data::Bool(first_elem != second_elem);
+                consts[dest as usize] = Data::Bool(!heap.equal(first_elem, second_elem));
             }
             Instr::Sup(o1, o2, dest) => {
                 let first_elem = consts[o1 as usize];
@@ -199,9 +568,11 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
                         consts[dest as usize] = Data::Bool(parent > child);
                     }
                     _ => {
-                        error!(format_args!(
-                            "UNSUPPORTED OPERATION: {:?} > {:?}",
-                            first_elem, second_elem
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: {:?} > {:?}{}",
+                            first_elem,
+                            second_elem,
+                            debug.at(i)
                         ));
                     }
                 }
@@ -215,9 +586,11 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
                         consts[dest as usize] = Data::Bool(parent >= child);
                     }
                     _ => {
-                        error!(format_args!(
-                            "UNSUPPORTED OPERATION: {:?} >= {:?}",
-                            first_elem, second_elem
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: {:?} >= {:?}{}",
+                            first_elem,
+                            second_elem,
+                            debug.at(i)
                         ));
                     }
                 }
@@ -230,9 +603,11 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
                         consts[dest as usize] = Data::Bool(parent < child);
                     }
                     _ => {
-                        error!(format_args!(
-                            "UNSUPPORTED OPERATION: {:?} < {:?}",
-                            first_elem, second_elem
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: {:?} < {:?}{}",
+                            first_elem,
+                            second_elem,
+                            debug.at(i)
                         ));
                     }
                 }
@@ -246,9 +621,11 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
                         consts[dest as usize] = Data::Bool(parent <= child);
                     }
                     _ => {
-                        error!(format_args!(
-                            "UNSUPPORTED OPERATION: {:?} <= {:?}",
-                            first_elem, second_elem
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: {:?} <= {:?}{}",
+                            first_elem,
+                            second_elem,
+                            debug.at(i)
                         ));
                     }
                 }
@@ -262,9 +639,11 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
                         consts[dest as usize] = Data::Bool(parent && child);
                     }
                     _ => {
-                        error!(format_args!(
-                            "UNSUPPORTED OPERATION: {:?} && {:?}",
-                            first_elem, second_elem
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: {:?} && {:?}{}",
+                            first_elem,
+                            second_elem,
+                            debug.at(i)
                         ));
                     }
                 }
@@ -278,31 +657,319 @@ fn execute(instructions: &[Instr], consts: &mut [Data]) {
                         consts[dest as usize] = Data::Bool(parent || child)
                     }
                     _ => {
-                        error!(format_args!(
-                            "UNSUPPORTED OPERATION: {:?} || {:?}",
-                            first_elem, second_elem
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: {:?} || {:?}{}",
+                            first_elem,
+                            second_elem,
+                            debug.at(i)
                         ));
                     }
                 }
             }
+            Instr::Neg(tgt, dest) => match consts[tgt as usize] {
+                Data::Number(x) => consts[dest as usize] = Data::Number(-x),
+                other => {
+                    fail!(format_args!(
+                        "UNSUPPORTED OPERATION: -{other:?}{}",
+                        debug.at(i)
+                    ));
+                }
+            },
+            Instr::Not(tgt, dest) => match consts[tgt as usize] {
+                Data::Bool(x) => consts[dest as usize] = Data::Bool(!x),
+                other => {
+                    fail!(format_args!(
+                        "UNSUPPORTED OPERATION: !{other:?}{}",
+                        debug.at(i)
+                    ));
+                }
+            },
             Instr::Mov(tgt, dest) => {
                 consts[dest as usize] = consts[tgt as usize];
             }
+            Instr::CallMethod(base, method, dest) => {
+                let base = base as usize;
+                consts[dest as usize] = methods::call(
+                    method,
+                    consts[base],
+                    &consts[base + 1..],
+                    heap,
+                    &mut usage,
+                    limits,
+                )
+                .map_err(|e| e.at(debug.at(i)))?;
+            }
             Instr::Print(target) => {
                 let elem = consts[target as usize];
-                println!("PRINTING => {elem:?}");
+                println!("{}", heap.show(elem));
+            }
+            Instr::ArgCount(dest) => {
+                consts[dest as usize] = Data::Number(script_args().len() as f64);
+            }
+            Instr::Arg(index, dest) => {
+                let args = script_args();
+                match consts[index as usize] {
+                    Data::Number(x)
+                        if x.fract() == 0.0 && x >= 0.0 && (x as usize) < args.len() =>
+                    {
+                        consts[dest as usize] =
+                            Data::String(Intern::from_ref(args[x as usize].as_str()));
+                    }
+                    other => {
+                        fail!(format_args!(
+                            "Invalid argument index {other} ({} arguments were given){}",
+                            args.len(),
+                            debug.at(i)
+                        ));
+                    }
+                }
+            }
+            Instr::Env(name, dest) => {
+                let Data::String(name) = consts[name as usize] else {
+                    fail!(format_args!(
+                        "Environment variable names must be strings, got {:?}{}",
+                        consts[name as usize],
+                        debug.at(i)
+                    ));
+                };
+                consts[dest as usize] = match std::env::var(name.as_str()) {
+                    Ok(value) => {
+                        usage.count_string(value.len(), limits)?;
+                        Data::String(Intern::from(value))
+                    }
+                    Err(_) => Data::Null,
+                };
+            }
+            Instr::SetEnv(name, value) => {
+                let Data::String(name) = consts[name as usize] else {
+                    fail!(format_args!(
+                        "Environment variable names must be strings, got {:?}{}",
+                        consts[name as usize],
+                        debug.at(i)
+                    ));
+                };
+                std::env::set_var(name.as_str(), heap.show(consts[value as usize]).to_string());
+            }
+            Instr::NewArray(base, len, dest) => {
+                let base = base as usize;
+                let elements = consts[base..base + len as usize].to_vec();
+                consts[dest as usize] = arrays::new(elements, heap, &mut usage, limits)
+                    .map_err(|e| e.at(debug.at(i)))?;
+                heap.collect_if_needed(&[consts, &saved, &initial]);
+            }
+            Instr::Index(target, index, dest) => {
+                consts[dest as usize] = arrays::index(
+                    consts[target as usize],
+                    consts[index as usize],
+                    heap,
+                    &mut usage,
+                    limits,
+                )
+                .map_err(|e| e.at(debug.at(i)))?;
+            }
+            Instr::Slice(target, bounds, dest) => {
+                let bounds = bounds as usize;
+                consts[dest as usize] = arrays::slice(
+                    consts[target as usize],
+                    consts[bounds],
+                    consts[bounds + 1],
+                    heap,
+                    &mut usage,
+                    limits,
+                )
+                .map_err(|e| e.at(debug.at(i)))?;
+                heap.collect_if_needed(&[consts, &saved, &initial]);
+            }
+            Instr::SetIndex(target, index, value) => {
+                arrays::set(
+                    consts[target as usize],
+                    consts[index as usize],
+                    consts[value as usize],
+                    heap,
+                )
+                .map_err(|e| RuntimeError::from(e).at(debug.at(i)))?;
+            }
+            Instr::Range(base, dest) => {
+                let base = base as usize;
+                consts[dest as usize] = arrays::range(
+                    consts[base],
+                    consts[base + 1],
+                    consts[base + 2],
+                    heap,
+                    &mut usage,
+                    limits,
+                )
+                .map_err(|e| e.at(debug.at(i)))?;
+                heap.collect_if_needed(&[consts, &saved, &initial]);
+            }
+            Instr::OpenFile(path, dest) => {
+                // the file is only read or written by its methods
+                let Data::String(path) = consts[path as usize] else {
+                    fail!(format_args!(
+                        "File paths must be strings, got {:?}{}",
+                        consts[path as usize],
+                        debug.at(i)
+                    ));
+                };
+                consts[dest as usize] = Data::File(path);
+            }
+            Instr::Input(prompt, dest) => {
+                if consts[prompt as usize] != Data::Null {
+                    print!("{}", consts[prompt as usize]);
+                    io::stdout().flush().unwrap();
+                }
+                let mut line = String::new();
+                consts[dest as usize] = match io::stdin().read_line(&mut line) {
+                    Ok(0) | Err(_) => Data::Null,
+                    Ok(_) => {
+                        let line = line.trim_end_matches(['\n', '\r']);
+                        usage.count_string(line.len(), limits)?;
+                        Data::String(Intern::from_ref(line))
+                    }
+                };
+            }
+            Instr::ForRange(base, var, size) => {
+                let base = base as usize;
+                match (consts[base], consts[base + 1], consts[base + 2]) {
+                    (Data::Number(current), Data::Number(end), Data::Number(step)) => {
+                        if step == 0.0 {
+                            fail!(format_args!("range() step cannot be 0{}", debug.at(i)));
+                        }
+                        if (step > 0.0 && current < end) || (step < 0.0 && current > end) {
+                            consts[var as usize] = Data::Number(current);
+                            consts[base] = Data::Number(current + step);
+                        } else {
+                            i += size as usize;
+                            continue;
+                        }
+                    }
+                    (start, end, step) => {
+                        fail!(format_args!(
+                            "UNSUPPORTED OPERATION: range({start:?}, {end:?}, {step:?}){}",
+                            debug.at(i)
+                        ));
+                    }
+                }
+            }
+            Instr::ForIn(iterable, state, size) => {
+                let state = state as usize;
+                // only hand-written or corrupted programs give another state
+                let Data::Number(position) = consts[state] else {
+                    fail!(format_args!(
+                        "Invalid for-in loop state {:?}{}",
+                        consts[state],
+                        debug.at(i)
+                    ));
+                };
+                let position = position as usize;
+                match consts[iterable as usize] {
+                    Data::Array(array) => {
+                        if let Some(x) = heap.get(array).get(position) {
+                            consts[state + 1] = *x;
+                            consts[state] = Data::Number((position + 1) as f64);
+                        } else {
+                            i += size as usize;
+                            continue;
+                        }
+                    }
+                    Data::String(str) => {
+                        let Some(rest) = str.get(position..) else {
+                            fail!(format_args!(
+                                "Invalid for-in loop state {:?}{}",
+                                consts[state],
+                                debug.at(i)
+                            ));
+                        };
+                        if let Some(c) = rest.chars().next() {
+                            usage.count_string(c.len_utf8(), limits)?;
+                            consts[state + 1] = Data::String(Intern::from(c.to_string()));
+                            consts[state] = Data::Number((position + c.len_utf8()) as f64);
+                        } else {
+                            i += size as usize;
+                            continue;
+                        }
+                    }
+                    other => {
+                        fail!(format_args!("Cannot iterate over {other:?}{}", debug.at(i)));
+                    }
+                }
+            }
+            Instr::Exit(code) => match consts[code as usize] {
+                Data::Number(x)
+                    if x.fract() == 0.0 && x >= i32::MIN as f64 && x <= i32::MAX as f64 =>
+                {
+                    io::stdout().flush().unwrap();
+                    std::process::exit(x as i32);
+                }
+                other => {
+                    fail!(format_args!("Invalid exit code {other:?}{}", debug.at(i)));
+                }
+            },
+            Instr::Call(function, args, dest) => {
+                if frames.len() >= limits.max_depth {
+                    return Err(LimitExceeded(format!(
+                        "Maximum call depth exceeded ({} nested calls)",
+                        limits.max_depth
+                    ))
+                    .into());
+                }
+                let func = &functions[function as usize];
+                let (lo, hi) = (func.registers.start as usize, func.registers.end as usize);
+                let args = args as usize;
+                // the arguments may live in the registers being reset (recursive calls)
+                call_args.clear();
+                call_args.extend_from_slice(&consts[args..args + func.params as usize]);
+                saved.extend_from_slice(&consts[lo..hi]);
+                consts[lo..hi].copy_from_slice(&initial[lo..hi]);
+                consts[lo..lo + call_args.len()].copy_from_slice(&call_args);
+                frames.push((i + 1, dest, function));
+                i = func.start;
+                continue;
+            }
+            Instr::Ret(value) => {
+                let value = consts[value as usize];
+                let Some((ret, dest, function)) = frames.pop() else {
+                    fail!(format_args!(
+                        "Cannot return from outside a function{}",
+                        debug.at(i)
+                    ));
+                };
+                let registers = &functions[function as usize].registers;
+                let (lo, hi) = (registers.start as usize, registers.end as usize);
+                let from = saved.len() - (hi - lo);
+                consts[lo..hi].copy_from_slice(&saved[from..]);
+                saved.truncate(from);
+                consts[dest as usize] = value;
+                i = ret;
+                continue;
             }
             Instr::Null => {
-                error!("NULL INSTRUCTION");
+                fail!(format_args!("NULL INSTRUCTION{}", debug.at(i)));
             }
         }
         i += 1;
     }
+    Ok(())
+}
+
+/// A node of the syntax tree and the code it comes from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum Expr {
+pub enum ExprKind {
     Num(f64),
+    // number literal written without a decimal point or an exponent
+    Int(i64),
     Bool(bool),
     Op(Box<Expr>, Box<[(Opcode, Box<Expr>)]>),
     Opcode(Opcode),
@@ -312,11 +979,37 @@ pub enum Expr {
     // Group(Box<[Expr]>),
     VarDeclare(String, Box<Expr>),
     VarAssign(String, Box<Expr>),
+    // `x += 1`,...
+    CompoundAssign(String, Opcode, Box<Expr>),
     // condition - code -- else_if_blocks(condition array) - else_block
     Condition(Box<Expr>, Box<[Expr]>, Box<[Expr]>, Option<Box<[Expr]>>),
     ElseIfBlock(Box<Expr>, Box<[Expr]>),
+    // last expression of a branch, written without `;`: the value of an if used as a value
+    BranchValue(Box<Expr>),
     WhileBlock(Box<Expr>, Box<[Expr]>),
+    // variable -- iterable -- code
+    ForLoop(String, Box<Expr>, Box<[Expr]>),
     FunctionCall(String, Box<[Expr]>),
+    // receiver -- method -- arguments
+    MethodCall(Box<Expr>, String, Box<[Expr]>),
+    Array(Box<[Expr]>),
+    // array -- index
+    Index(Box<Expr>, Box<Expr>),
+    // array -- start -- end
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>),
+    // array -- index -- value
+    IndexAssign(Box<Expr>, Box<Expr>, Box<Expr>),
+    // name -- parameters -- code
+    FunctionDecl(String, Box<[String]>, Box<[Expr]>),
+    Return(Option<Box<Expr>>),
+    // path of the imported file, relative to the importing one
+    Import(String),
+    Break,
+    Continue,
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    // code that couldn't be parsed, never compiled
+    Error,
     LPAREN,
     RPAREN,
 }
@@ -340,10 +1033,31 @@ pub enum Opcode {
     BoolOr,
 }
 
-lalrpop_mod!(pub grammar);
+lalrpop_mod!(
+    #[allow(clippy::ptr_arg)] // the generated parser takes the error list as `&mut Vec`
+    pub grammar
+);
+
+/// Negates literals directly instead of doing it at runtime
+pub fn fold_neg(x: Box<Expr>, span: Span) -> Box<Expr> {
+    let kind = match x.kind {
+        ExprKind::Num(num) => ExprKind::Num(-num),
+        ExprKind::Int(num) => ExprKind::Int(-num),
+        _ => ExprKind::Neg(x),
+    };
+    Box::new(Expr::new(kind, span))
+}
+
+pub fn fold_not(x: Box<Expr>, span: Span) -> Box<Expr> {
+    let kind = match x.kind {
+        ExprKind::Bool(bool) => ExprKind::Bool(!bool),
+        _ => ExprKind::Not(x),
+    };
+    Box::new(Expr::new(kind, span))
+}
 
-fn get_precedence(operator: Expr) -> u8 {
-    if let Expr::Opcode(op) = operator {
+fn get_precedence(operator: &Expr) -> u8 {
+    if let ExprKind::Opcode(op) = operator.kind {
         match op {
             Opcode::BoolOr => 1,
             Opcode::BoolAnd => 2,
@@ -366,8 +1080,8 @@ fn get_precedence(operator: Expr) -> u8 {
     }
 }
 
-fn is_left_associative(operator: Expr) -> bool {
-    if let Expr::Opcode(op) = operator {
+fn is_left_associative(operator: &Expr) -> bool {
+    if let ExprKind::Opcode(op) = operator.kind {
         !matches!(op, Opcode::Pow)
     } else {
         unreachable!()
@@ -377,36 +1091,35 @@ fn is_left_associative(operator: Expr) -> bool {
 pub fn op_to_rpn(operation_input: Vec<Expr>) -> Vec<Expr> {
     let mut return_vector: Vec<Expr> = Vec::new();
     let mut op_stack: Vec<Expr> = Vec::new();
+    let is_lparen = |x: &Expr| x.kind == ExprKind::LPAREN;
     for x in operation_input {
-        // num, function,...
-        if !matches!(x, Expr::Opcode(_) | Expr::LPAREN | Expr::RPAREN) {
-            return_vector.push(x);
-        } else if matches!(x, Expr::Opcode(_)) && x != Expr::LPAREN && x != Expr::RPAREN {
-            // operator
-            while !op_stack.is_empty()
-                && op_stack.last().unwrap() != &Expr::LPAREN
-                && (get_precedence(op_stack.last().unwrap().clone()) > get_precedence(x.clone())
-                    || (get_precedence(op_stack.last().unwrap().clone())
-                        == get_precedence(x.clone())
-                        && is_left_associative(x.clone())))
-            {
-                return_vector.push(op_stack.pop().unwrap());
-            }
-            op_stack.push(x);
-        } else if x == Expr::LPAREN {
-            op_stack.push(x);
-        } else if x == Expr::RPAREN {
-            while op_stack.last().unwrap() != &Expr::LPAREN {
-                assert!(!op_stack.is_empty(), "MISMATCHED PARENTHESES");
-                return_vector.push(op_stack.pop().unwrap());
-            }
-            op_stack.pop();
+        match x.kind {
+            ExprKind::Opcode(_) => {
+                while !op_stack.is_empty()
+                    && !is_lparen(op_stack.last().unwrap())
+                    && (get_precedence(op_stack.last().unwrap()) > get_precedence(&x)
+                        || (get_precedence(op_stack.last().unwrap()) == get_precedence(&x)
+                            && is_left_associative(&x)))
+                {
+                    return_vector.push(op_stack.pop().unwrap());
+                }
+                op_stack.push(x);
+            }
+            ExprKind::LPAREN => op_stack.push(x),
+            ExprKind::RPAREN => {
+                while !is_lparen(op_stack.last().unwrap()) {
+                    assert!(!op_stack.is_empty(), "MISMATCHED PARENTHESES");
+                    return_vector.push(op_stack.pop().unwrap());
+                }
+                op_stack.pop();
+            }
+            // num, function,...
+            _ => return_vector.push(x),
         }
     }
     while !op_stack.is_empty() {
-        assert_ne!(
-            op_stack.last().unwrap(),
-            &Expr::LPAREN,
+        assert!(
+            !is_lparen(op_stack.last().unwrap()),
             "MISMATCHED PARENTHESES"
         );
         return_vector.push(op_stack.pop().unwrap());
@@ -415,24 +1128,37 @@ pub fn op_to_rpn(operation_input: Vec<Expr>) -> Vec<Expr> {
     return_vector
 }
 
-fn get_tgt_id(x: Instr) -> u16 {
+fn get_tgt_id(x: Instr) -> Option<u16> {
     match x {
-        Instr::Mov(_, y) => y,
-        Instr::Add(_, _, y) => y,
-        Instr::Mul(_, _, y) => y,
-        Instr::Sub(_, _, y) => y,
-        Instr::Div(_, _, y) => y,
-        Instr::Mod(_, _, y) => y,
-        Instr::Pow(_, _, y) => y,
-        Instr::Eq(_, _, y) => y,
-        Instr::NotEq(_, _, y) => y,
-        Instr::Sup(_, _, y) => y,
-        Instr::SupEq(_, _, y) => y,
-        Instr::Inf(_, _, y) => y,
-        Instr::InfEq(_, _, y) => y,
-        Instr::BoolAnd(_, _, y) => y,
-        Instr::BoolOr(_, _, y) => y,
-        _ => unreachable!(),
+        Instr::Mov(_, y) => Some(y),
+        Instr::Add(_, _, y) => Some(y),
+        Instr::Mul(_, _, y) => Some(y),
+        Instr::Sub(_, _, y) => Some(y),
+        Instr::Div(_, _, y) => Some(y),
+        Instr::Mod(_, _, y) => Some(y),
+        Instr::Pow(_, _, y) => Some(y),
+        Instr::Eq(_, _, y) => Some(y),
+        Instr::NotEq(_, _, y) => Some(y),
+        Instr::Sup(_, _, y) => Some(y),
+        Instr::SupEq(_, _, y) => Some(y),
+        Instr::Inf(_, _, y) => Some(y),
+        Instr::InfEq(_, _, y) => Some(y),
+        Instr::BoolAnd(_, _, y) => Some(y),
+        Instr::BoolOr(_, _, y) => Some(y),
+        Instr::ArgCount(y) => Some(y),
+        Instr::Arg(_, y) => Some(y),
+        Instr::Env(_, y) => Some(y),
+        Instr::Call(_, _, y) => Some(y),
+        Instr::Neg(_, y) => Some(y),
+        Instr::Not(_, y) => Some(y),
+        Instr::CallMethod(_, _, y) => Some(y),
+        Instr::OpenFile(_, y) => Some(y),
+        Instr::Input(_, y) => Some(y),
+        Instr::NewArray(_, _, y) => Some(y),
+        Instr::Index(_, _, y) => Some(y),
+        Instr::Slice(_, _, y) => Some(y),
+        Instr::Range(_, y) => Some(y),
+        _ => None,
     }
 }
 
@@ -456,6 +1182,19 @@ fn move_to_id(x: &mut [Instr], tgt_id: u16) {
         Instr::InfEq(_, _, z) => *z = tgt_id,
         Instr::BoolAnd(_, _, z) => *z = tgt_id,
         Instr::BoolOr(_, _, z) => *z = tgt_id,
+        Instr::ArgCount(z) => *z = tgt_id,
+        Instr::Arg(_, z) => *z = tgt_id,
+        Instr::Env(_, z) => *z = tgt_id,
+        Instr::Call(_, _, z) => *z = tgt_id,
+        Instr::Neg(_, z) => *z = tgt_id,
+        Instr::Not(_, z) => *z = tgt_id,
+        Instr::CallMethod(_, _, z) => *z = tgt_id,
+        Instr::OpenFile(_, z) => *z = tgt_id,
+        Instr::Input(_, z) => *z = tgt_id,
+        Instr::NewArray(_, _, z) => *z = tgt_id,
+        Instr::Index(_, _, z) => *z = tgt_id,
+        Instr::Slice(_, _, z) => *z = tgt_id,
+        Instr::Range(_, z) => *z = tgt_id,
         _ => unreachable!(),
     }
 }
@@ -482,223 +1221,1081 @@ macro_rules! handle_ops {
     };
 }
 
-fn get_id(x: Expr, variables: &mut [(String, u16)], consts: &mut Vec<Data>) -> (u16, bool) {
+/// Index of a register, which has to fit in the operands of the instructions
+fn register_id(id: usize, ctx: &Context) -> Result<u16, Error> {
+    let Ok(id) = u16::try_from(id) else {
+        fail!(
+            format_args!(
+                "The program needs more than {} registers{}",
+                u16::MAX as usize + 1,
+                ctx.at()
+            ),
+            "Use loops instead of repeating code, or reuse variables instead of declaring new ones"
+        );
+    };
+    Ok(id)
+}
+
+/// Allocates a new register, initialized to null
+fn new_register(consts: &mut Vec<Data>, ctx: &Context) -> Result<u16, Error> {
+    consts.push(Data::Null);
+    register_id(consts.len() - 1, ctx)
+}
+
+/// Size of a jump over `len` instructions, which has to fit in the operands of the instructions
+fn jump_size(len: usize, ctx: &Context) -> Result<u16, Error> {
+    let Ok(size) = u16::try_from(len) else {
+        fail!(
+            format_args!(
+                "This code is too long to jump over ({len} instructions){}",
+                ctx.at()
+            ),
+            "Move parts of it into functions declared outside of it"
+        );
+    };
+    Ok(size)
+}
+
+fn get_var_id(name: &str, variables: &[(String, u16)], ctx: &Context) -> Result<u16, Error> {
+    if let Some((_, id)) = variables.iter().rev().find(|(x, _)| name == x) {
+        Ok(*id)
+    } else {
+        fail!(format_args!("Unknown variable {}{}", name.red(), ctx.at()));
+    }
+}
+
+/// Value of a literal, known at compile time
+fn literal(x: &ExprKind) -> Option<Data> {
+    match x {
+        ExprKind::Num(num) => Some(Data::Number(*num)),
+        ExprKind::Int(num) => Some(Data::Number(*num as f64)),
+        ExprKind::String(str) => Some(Data::String(Intern::from_ref(str))),
+        ExprKind::Bool(bool) => Some(Data::Bool(*bool)),
+        _ => None,
+    }
+}
+
+/// What a statement is, for error messages
+fn describe(x: &ExprKind) -> &'static str {
     match x {
-        Expr::Num(num) => {
-            consts.push(Data::Number(num));
-            ((consts.len() - 1) as u16, false)
+        ExprKind::VarDeclare(_, _) => "A variable declaration",
+        ExprKind::VarAssign(_, _)
+        | ExprKind::CompoundAssign(_, _, _)
+        | ExprKind::IndexAssign(_, _, _) => "An assignment",
+        ExprKind::Condition(_, _, _, _) | ExprKind::ElseIfBlock(_, _) => "An if block",
+        ExprKind::WhileBlock(_, _) => "A while loop",
+        ExprKind::ForLoop(_, _, _) => "A for loop",
+        ExprKind::FunctionDecl(_, _, _) => "A function declaration",
+        ExprKind::Return(_) => "A return",
+        ExprKind::Import(_) => "An import",
+        ExprKind::Break => "A break",
+        ExprKind::Continue => "A continue",
+        _ => "This code",
+    }
+}
+
+/// Compiles an expression and returns the register that will hold its value
+fn expr_to_id(
+    x: Expr,
+    variables: &mut Vec<(String, u16)>,
+    consts: &mut Vec<Data>,
+    ctx: &mut Context,
+    output: &mut Code,
+) -> Result<u16, Error> {
+    let outer = enter_span(x.span, ctx, output);
+    let id = kind_to_id(x.kind, variables, consts, ctx, output)?;
+    enter_span(outer, ctx, output);
+    Ok(id)
+}
+
+fn kind_to_id(
+    x: ExprKind,
+    variables: &mut Vec<(String, u16)>,
+    consts: &mut Vec<Data>,
+    ctx: &mut Context,
+    output: &mut Code,
+) -> Result<u16, Error> {
+    if let Some(value) = literal(&x) {
+        consts.push(value);
+        return register_id(consts.len() - 1, ctx);
+    }
+    let id = match x {
+        ExprKind::Var(name) => get_var_id(&name, variables, ctx)?,
+        ExprKind::Priority(x) => expr_to_id(*x, variables, consts, ctx, output)?,
+        ExprKind::Neg(x) => {
+            let id = expr_to_id(*x, variables, consts, ctx, output)?;
+            let dest = new_register(consts, ctx)?;
+            output.push(Instr::Neg(id, dest));
+            dest
+        }
+        ExprKind::Not(x) => {
+            let id = expr_to_id(*x, variables, consts, ctx, output)?;
+            let dest = new_register(consts, ctx)?;
+            output.push(Instr::Not(id, dest));
+            dest
+        }
+        ExprKind::FunctionCall(name, args) => {
+            let Some(id) = call_to_instr(&name, args, variables, consts, ctx, output)? else {
+                fail!(format_args!(
+                    "Function {} does not return a value{}",
+                    name.red(),
+                    ctx.at()
+                ));
+            };
+            id
+        }
+        ExprKind::MethodCall(receiver, name, args) => {
+            let Some((method, arity)) = methods::find(&name) else {
+                // the type of the receiver is only known when running
+                fail!(format_args!(
+                    "No type has a method {}{}",
+                    name.red(),
+                    ctx.at()
+                ));
+            };
+            if args.len() != arity {
+                fail!(format_args!(
+                    "Method {} expected {arity} arguments, got {}{}",
+                    name.red(),
+                    args.len(),
+                    ctx.at()
+                ));
+            }
+            // the receiver and the arguments are passed in consecutive registers
+            let base = register_id(consts.len(), ctx)?;
+            for _ in 0..=arity {
+                new_register(consts, ctx)?;
+            }
+            expr_to_dest(*receiver, base, variables, consts, ctx, output)?;
+            for (i, arg) in args.into_vec().into_iter().enumerate() {
+                expr_to_dest(arg, base + 1 + i as u16, variables, consts, ctx, output)?;
+            }
+            let dest = new_register(consts, ctx)?;
+            output.push(Instr::CallMethod(base, method, dest));
+            dest
+        }
+        ExprKind::Array(elements) => {
+            // the elements are stored in consecutive registers
+            let base = register_id(consts.len(), ctx)?;
+            for _ in 0..elements.len() {
+                new_register(consts, ctx)?;
+            }
+            let len = elements.len() as u16;
+            for (i, x) in elements.into_vec().into_iter().enumerate() {
+                expr_to_dest(x, base + i as u16, variables, consts, ctx, output)?;
+            }
+            let dest = new_register(consts, ctx)?;
+            output.push(Instr::NewArray(base, len, dest));
+            dest
+        }
+        ExprKind::Index(target, index) => {
+            let target = expr_to_id(*target, variables, consts, ctx, output)?;
+            let index = expr_to_id(*index, variables, consts, ctx, output)?;
+            let dest = new_register(consts, ctx)?;
+            output.push(Instr::Index(target, index, dest));
+            dest
+        }
+        ExprKind::Slice(target, start, end) => {
+            let target = expr_to_id(*target, variables, consts, ctx, output)?;
+            // missing bounds stay null
+            let bounds = new_register(consts, ctx)?;
+            new_register(consts, ctx)?;
+            if let Some(start) = start {
+                expr_to_dest(*start, bounds, variables, consts, ctx, output)?;
+            }
+            if let Some(end) = end {
+                expr_to_dest(*end, bounds + 1, variables, consts, ctx, output)?;
+            }
+            let dest = new_register(consts, ctx)?;
+            output.push(Instr::Slice(target, bounds, dest));
+            dest
+        }
+        ExprKind::Condition(condition, code, else_ifs, else_block) => {
+            let dest = new_register(consts, ctx)?;
+            let branches = branches(*condition, code, else_ifs, else_block);
+            condition_to_instr(branches, Some(dest), variables, consts, ctx, output)?;
+            dest
+        }
+        ExprKind::Op(_, _) => {
+            let op = op_to_rpn(flatten_op(ctx.expr(x)));
+            log!("OP {op:?}");
+
+            // registers holding the operands that haven't been used yet
+            let mut stack: Vec<u16> = Vec::new();
+            for x in op {
+                if let ExprKind::Opcode(op) = x.kind {
+                    let second = stack.pop().unwrap();
+                    let first = stack.pop().unwrap();
+                    let dest = new_register(consts, ctx)?;
+                    handle_ops!(output, first, second, dest, op);
+                    stack.push(dest);
+                } else {
+                    stack.push(expr_to_id(x, variables, consts, ctx, output)?);
+                }
+            }
+            stack.pop().unwrap()
         }
-        Expr::String(str) => {
-            consts.push(Data::String(Intern::from(str)));
-            ((consts.len() - 1) as u16, false)
+        other => {
+            fail!(format_args!(
+                "{} is not a value{}",
+                describe(&other),
+                ctx.at()
+            ));
+        }
+    };
+    Ok(id)
+}
+
+/// Compiles an expression so that its value ends up in the `dest` register
+fn expr_to_dest(
+    x: Expr,
+    dest: u16,
+    variables: &mut Vec<(String, u16)>,
+    consts: &mut Vec<Data>,
+    ctx: &mut Context,
+    output: &mut Code,
+) -> Result<(), Error> {
+    let mut x = x;
+    while let ExprKind::Priority(inner) = x.kind {
+        x = *inner;
+    }
+    if let ExprKind::Condition(condition, code, else_ifs, else_block) = x.kind {
+        // every branch writes its value to `dest`, so the last instruction isn't the only one
+        // producing it
+        let outer = enter_span(x.span, ctx, output);
+        let branches = branches(*condition, code, else_ifs, else_block);
+        condition_to_instr(branches, Some(dest), variables, consts, ctx, output)?;
+        enter_span(outer, ctx, output);
+        return Ok(());
+    }
+    let start = output.len();
+    let id = expr_to_id(x, variables, consts, ctx, output)?;
+    // write the result directly instead of going through a temporary register
+    if output.len() > start && get_tgt_id(*output.last().unwrap()) == Some(id) {
+        move_to_id(output, dest);
+    } else {
+        output.push(Instr::Mov(id, dest));
+    }
+    Ok(())
+}
+
+/// Turns an operation into a flat list of operands, operators and parentheses
+fn flatten_op(op: Expr) -> Vec<Expr> {
+    fn remove_priority(x: Expr) -> Vec<Expr> {
+        match x.kind {
+            ExprKind::Op(_, _) => flatten_op(x),
+            ExprKind::Priority(inner) => {
+                let mut output: Vec<Expr> = vec![];
+                output.push(Expr::new(ExprKind::LPAREN, x.span));
+                output.extend(remove_priority(*inner));
+                output.push(Expr::new(ExprKind::RPAREN, x.span));
+                output
+            }
+            _ => vec![x],
         }
-        Expr::Bool(bool) => {
-            consts.push(Data::Bool(bool));
-            ((consts.len() - 1) as u16, false)
+    }
+
+    let mut operation: Vec<Expr> = vec![];
+    if let ExprKind::Op(left, right) = op.kind {
+        operation.extend(remove_priority(*left));
+        for (opcode, val) in right.into_vec() {
+            operation.push(Expr::new(ExprKind::Opcode(opcode), op.span));
+            operation.extend(remove_priority(*val));
         }
-        Expr::Var(name) => {
-            if let Some((_, id)) = variables.iter().find(|(x, _)| &name == x) {
-                (*id, true)
+    }
+    log!("TEMPOP {operation:?}");
+    operation
+}
+
+/// Names that can't be used by user-defined functions
+const BUILTINS: &[&str] = &["print", "input", "args", "env", "set_env", "exit", "range"];
+
+/// Checks the number of arguments given to a function
+fn check_args(
+    name: &str,
+    args: &[Expr],
+    expected: std::ops::RangeInclusive<usize>,
+    ctx: &Context,
+) -> Result<(), Error> {
+    if !expected.contains(&args.len()) {
+        let expected = if expected.start() == expected.end() {
+            expected.start().to_string()
+        } else {
+            format!("{} to {}", expected.start(), expected.end())
+        };
+        fail!(format_args!(
+            "Function {} expected {expected} arguments, got {}{}",
+            name.red(),
+            args.len(),
+            ctx.at()
+        ));
+    }
+    Ok(())
+}
+
+/// Compiles a function call, returns the register holding the returned value (if any)
+fn call_to_instr(
+    name: &str,
+    args: Box<[Expr]>,
+    variables: &mut Vec<(String, u16)>,
+    consts: &mut Vec<Data>,
+    ctx: &mut Context,
+    output: &mut Code,
+) -> Result<Option<u16>, Error> {
+    if let Some((namespace, func)) = name.rsplit_once("::") {
+        match namespace {
+            "math" => return math_to_instr(name, func, args, variables, consts, ctx, output),
+            "io" => return io_to_instr(name, func, args, variables, consts, ctx, output),
+            // functions of imported files
+            _ => {}
+        }
+    }
+    let mut args = args.into_vec();
+    let id = match name {
+        "print" => {
+            for arg in args {
+                let id = expr_to_id(arg, variables, consts, ctx, output)?;
+                output.push(Instr::Print(id));
+            }
+            None
+        }
+        "input" => {
+            check_args(name, &args, 0..=1, ctx)?;
+            let prompt = match args.pop() {
+                Some(prompt) => expr_to_id(prompt, variables, consts, ctx, output)?,
+                None => new_register(consts, ctx)?,
+            };
+            let dest = new_register(consts, ctx)?;
+            output.push(Instr::Input(prompt, dest));
+            Some(dest)
+        }
+        "args" => {
+            check_args(name, &args, 0..=1, ctx)?;
+            if let Some(index) = args.pop() {
+                let index = expr_to_id(index, variables, consts, ctx, output)?;
+                let dest = new_register(consts, ctx)?;
+                output.push(Instr::Arg(index, dest));
+                Some(dest)
             } else {
-                error!(format_args!("Unknown variable {}", name.red()));
+                let dest = new_register(consts, ctx)?;
+                output.push(Instr::ArgCount(dest));
+                Some(dest)
+            }
+        }
+        "env" => {
+            check_args(name, &args, 1..=1, ctx)?;
+            let var = expr_to_id(args.pop().unwrap(), variables, consts, ctx, output)?;
+            let dest = new_register(consts, ctx)?;
+            output.push(Instr::Env(var, dest));
+            Some(dest)
+        }
+        "set_env" => {
+            check_args(name, &args, 2..=2, ctx)?;
+            let value = args.pop().unwrap();
+            let var = expr_to_id(args.pop().unwrap(), variables, consts, ctx, output)?;
+            let value = expr_to_id(value, variables, consts, ctx, output)?;
+            output.push(Instr::SetEnv(var, value));
+            None
+        }
+        "exit" => {
+            check_args(name, &args, 0..=1, ctx)?;
+            let code = match args.pop() {
+                Some(code) => expr_to_id(code, variables, consts, ctx, output)?,
+                None => {
+                    let zero = ctx.expr(ExprKind::Int(0));
+                    expr_to_id(zero, variables, consts, ctx, output)?
+                }
+            };
+            output.push(Instr::Exit(code));
+            None
+        }
+        "range" => {
+            let base = range_to_registers(args.into_boxed_slice(), variables, consts, ctx, output)?;
+            let dest = new_register(consts, ctx)?;
+            output.push(Instr::Range(base, dest));
+            Some(dest)
+        }
+        name => {
+            let Some(function) = ctx.find_function(name) else {
+                fail!(format_args!("Unknown function {}{}", name.red(), ctx.at()));
+            };
+            let params = ctx.functions[function].params as usize;
+            check_args(name, &args, params..=params, ctx)?;
+            // the arguments are passed in consecutive registers
+            let base = register_id(consts.len(), ctx)?;
+            for _ in 0..params {
+                new_register(consts, ctx)?;
+            }
+            for (i, arg) in args.into_iter().enumerate() {
+                expr_to_dest(arg, base + i as u16, variables, consts, ctx, output)?;
             }
+            let dest = new_register(consts, ctx)?;
+            output.push(Instr::Call(function as u16, base, dest));
+            Some(dest)
+        }
+    };
+    Ok(id)
+}
+
+/// Compiles the arguments of `range()` into 3 consecutive registers (start, end, step), returns
+/// the first one
+fn range_to_registers(
+    args: Box<[Expr]>,
+    variables: &mut Vec<(String, u16)>,
+    consts: &mut Vec<Data>,
+    ctx: &mut Context,
+    output: &mut Code,
+) -> Result<u16, Error> {
+    check_args("range", &args, 1..=3, ctx)?;
+    let mut args = args.into_vec();
+    let (start, end, step) = match args.len() {
+        1 => (
+            ctx.expr(ExprKind::Int(0)),
+            args.remove(0),
+            ctx.expr(ExprKind::Int(1)),
+        ),
+        2 => (args.remove(0), args.remove(0), ctx.expr(ExprKind::Int(1))),
+        _ => (args.remove(0), args.remove(0), args.remove(0)),
+    };
+    let base = new_register(consts, ctx)?;
+    new_register(consts, ctx)?;
+    new_register(consts, ctx)?;
+    expr_to_dest(start, base, variables, consts, ctx, output)?;
+    expr_to_dest(end, base + 1, variables, consts, ctx, output)?;
+    expr_to_dest(step, base + 2, variables, consts, ctx, output)?;
+    Ok(base)
+}
+
+/// Compiles a call to a function of the `math` namespace
+fn math_to_instr(
+    name: &str,
+    func: &str,
+    args: Box<[Expr]>,
+    variables: &mut Vec<(String, u16)>,
+    consts: &mut Vec<Data>,
+    ctx: &mut Context,
+    output: &mut Code,
+) -> Result<Option<u16>, Error> {
+    let mut args = args.into_vec();
+    let id = match func {
+        // same as the methods of numbers
+        "sqrt" | "abs" | "floor" | "ceil" | "round" => {
+            check_args(name, &args, 1..=1, ctx)?;
+            let x = ctx.expr(ExprKind::MethodCall(
+                Box::new(args.pop().unwrap()),
+                func.to_string(),
+                Box::new([]),
+            ));
+            Some(expr_to_id(x, variables, consts, ctx, output)?)
+        }
+        "pow" => {
+            check_args(name, &args, 2..=2, ctx)?;
+            let exponent = args.pop().unwrap();
+            let x = ctx.expr(ExprKind::Op(
+                Box::new(args.pop().unwrap()),
+                Box::new([(Opcode::Pow, Box::new(exponent))]),
+            ));
+            Some(expr_to_id(x, variables, consts, ctx, output)?)
+        }
+        _ => {
+            fail!(format_args!("Unknown function {}{}", name.red(), ctx.at()));
+        }
+    };
+    Ok(id)
+}
+
+/// Compiles a call to a function of the `io` namespace
+fn io_to_instr(
+    name: &str,
+    func: &str,
+    args: Box<[Expr]>,
+    variables: &mut Vec<(String, u16)>,
+    consts: &mut Vec<Data>,
+    ctx: &mut Context,
+    output: &mut Code,
+) -> Result<Option<u16>, Error> {
+    let mut args = args.into_vec();
+    let id = match func {
+        "open" => {
+            check_args(name, &args, 1..=1, ctx)?;
+            let path = expr_to_id(args.pop().unwrap(), variables, consts, ctx, output)?;
+            let dest = new_register(consts, ctx)?;
+            output.push(Instr::OpenFile(path, dest));
+            Some(dest)
+        }
+        _ => {
+            fail!(format_args!("Unknown function {}{}", name.red(), ctx.at()));
+        }
+    };
+    Ok(id)
+}
+
+/// Registers the functions declared in `input` before compiling it, so that they can be called
+/// before their declaration
+fn declare_functions(input: &[Expr], ctx: &mut Context) -> Result<(), Error> {
+    let first = ctx.functions.len();
+    for x in input {
+        let ExprKind::FunctionDecl(name, params, _) = &x.kind else {
+            continue;
+        };
+        ctx.span = x.span;
+        if BUILTINS.contains(&name.as_str()) {
+            fail!(format_args!(
+                "Cannot declare function {}, it is a built-in function{}",
+                name.red(),
+                ctx.at()
+            ));
+        }
+        let name = ctx.qualify(name);
+        if ctx.functions[first..].iter().any(|x| x.name == name) {
+            fail!(format_args!(
+                "Function {} is already declared{}",
+                name.red(),
+                ctx.at()
+            ));
         }
-        _ => todo!(""),
+        ctx.functions.push(Function {
+            name,
+            params: params.len() as u16,
+            start: 0,
+            registers: 0..0,
+        });
     }
+    Ok(())
 }
 
-macro_rules! print {
-    ($($x:tt)*) => {
-        #[cfg(debug_assertions)]
-        println!("\x1b[33m[LOG] {}\x1b[0m", format!($($x)*))
+// placeholders for `break` and `continue`, replaced once the loop is compiled (the compiler never
+// emits jumps of size 0 otherwise)
+const BREAK: Instr = Instr::Jmp(0, false);
+const CONTINUE: Instr = Instr::Jmp(0, true);
+
+/// Turns the `break`/`continue` placeholders found in the code of the loop that ends `output` into
+/// jumps to its end and to `start`
+fn patch_loop_jumps(
+    output: &mut [Instr],
+    code: usize,
+    start: usize,
+    ctx: &Context,
+) -> Result<(), Error> {
+    let end = output.len();
+    for (i, instr) in output.iter_mut().enumerate().skip(code) {
+        match *instr {
+            BREAK => *instr = Instr::Jmp(jump_size(end - i, ctx)?, false),
+            CONTINUE => *instr = Instr::Jmp(jump_size(i - start, ctx)?, true),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Condition and code of each branch of an `if`, `else` having no condition
+type Branches = Vec<(Option<Expr>, Box<[Expr]>)>;
+
+fn branches(
+    condition: Expr,
+    code: Box<[Expr]>,
+    else_ifs: Box<[Expr]>,
+    else_block: Option<Box<[Expr]>>,
+) -> Branches {
+    let mut branches = vec![(Some(condition), code)];
+    for x in else_ifs.into_vec() {
+        if let ExprKind::ElseIfBlock(condition, code) = x.kind {
+            branches.push((Some(*condition), code));
+        }
+    }
+    branches.extend(else_block.map(|code| (None, code)));
+    branches
+}
+
+/// Takes the value out of the code of a branch, `None` if the branch leaves the if another way
+fn branch_value(code: &mut Vec<Expr>, ctx: &mut Context) -> Result<Option<Expr>, Error> {
+    match code.last().map(|x| &x.kind) {
+        Some(ExprKind::BranchValue(_)) => match code.pop().unwrap().kind {
+            ExprKind::BranchValue(value) => Ok(Some(*value)),
+            _ => unreachable!(),
+        },
+        // an if whose branches give a value
+        Some(ExprKind::Condition(_, _, _, _)) => Ok(code.pop()),
+        Some(ExprKind::Return(_) | ExprKind::Break | ExprKind::Continue) => Ok(None),
+        _ => {
+            if let Some(last) = code.last() {
+                ctx.span = last.span;
+            }
+            fail!(
+                format_args!("A branch of an if used as a value has no value{}", ctx.at()),
+                "End the branch with an expression without `;`, such as `{ x + 1 }`"
+            );
+        }
+    }
+}
+
+/// Compiles an `if` and its `else if`/`else` branches. When it is used as a value, the last
+/// expression of the branch that runs is written to `dest`.
+fn condition_to_instr(
+    branches: Branches,
+    dest: Option<u16>,
+    variables: &mut Vec<(String, u16)>,
+    consts: &mut Vec<Data>,
+    ctx: &mut Context,
+    output: &mut Code,
+) -> Result<(), Error> {
+    if dest.is_some() && branches.last().unwrap().0.is_some() {
+        fail!(
+            format_args!("An if used as a value needs an else branch{}", ctx.at()),
+            "Add an `else { ... }` giving the value when no condition is true"
+        );
+    }
+    let count = branches.len();
+    // jumps from the end of each branch to the end of the condition
+    let mut exits: Vec<usize> = Vec::new();
+    for (i, (condition, code)) in branches.into_iter().enumerate() {
+        let check = match condition {
+            Some(x) => {
+                let id = expr_to_id(x, variables, consts, ctx, output)?;
+                output.push(Instr::Cmp(id, 0));
+                Some(output.len() - 1)
+            }
+            None => None,
+        };
+        let mut priv_vars = variables.clone();
+        let mut code = code.into_vec();
+        let value = match dest {
+            Some(dest) => branch_value(&mut code, ctx)?.map(|value| (value, dest)),
+            None => None,
+        };
+        let mut code = parser_to_instr_set(code, &mut priv_vars, consts, ctx)?;
+        if let Some((value, dest)) = value {
+            expr_to_dest(value, dest, &mut priv_vars, consts, ctx, &mut code)?;
+        }
+        output.extend(code);
+        if i + 1 < count {
+            output.push(Instr::Jmp(0, false));
+            exits.push(output.len() - 1);
+        }
+        // a false condition skips to the next branch
+        if let Some(check) = check {
+            if let Instr::Cmp(id, _) = output[check] {
+                output[check] = Instr::Cmp(id, jump_size(output.len() - check, ctx)?);
+            }
+        }
+    }
+    for exit in exits {
+        output[exit] = Instr::Jmp(jump_size(output.len() - exit, ctx)?, false);
     }
+    Ok(())
 }
 
 fn parser_to_instr_set(
     input: Vec<Expr>,
     variables: &mut Vec<(String, u16)>,
     consts: &mut Vec<Data>,
-) -> Vec<Instr> {
-    let mut output: Vec<Instr> = Vec::new();
+    ctx: &mut Context,
+) -> Result<Code, Error> {
+    let outer = ctx.span;
+    let mut output = Code::new(outer);
+    declare_functions(&input, ctx)?;
     for x in input {
-        match x {
-            Expr::Num(num) => consts.push(Data::Number(num)),
-            Expr::Bool(bool) => consts.push(Data::Bool(bool)),
-            Expr::String(str) => consts.push(Data::String(Intern::from(str))),
-            Expr::Condition(x, y, z, w) => {
-                let condition = parser_to_instr_set(vec![*x], variables, consts);
-                output.extend(condition);
-                let condition_id = get_tgt_id(*output.last().unwrap());
-                let mut priv_vars = variables.clone();
-                let cond_code = parser_to_instr_set(y.into_vec(), &mut priv_vars, consts);
-                let len = cond_code.len() + 1;
-                output.push(Instr::Cmp(condition_id, len as u16));
-                output.extend(cond_code);
-
-                print!("CONDITION IS {condition_id:?}");
-                // TODO
+        let span = x.span;
+        enter_span(span, ctx, &mut output);
+        // when the if isn't used as a value, the value of a branch is a regular expression
+        let x = match x.kind {
+            ExprKind::BranchValue(value) => *value,
+            _ => x,
+        };
+        match x.kind {
+            kind @ (ExprKind::Num(_)
+            | ExprKind::Int(_)
+            | ExprKind::Bool(_)
+            | ExprKind::String(_)) => consts.push(literal(&kind).unwrap()),
+            ExprKind::Condition(x, y, else_ifs, else_block) => {
+                let branches = branches(*x, y, else_ifs, else_block);
+                condition_to_instr(branches, None, variables, consts, ctx, &mut output)?;
             }
-            Expr::WhileBlock(x, y) => {
-                let condition = parser_to_instr_set(vec![*x], variables, consts);
-                output.extend(condition);
-                let condition_id = get_tgt_id(*output.last().unwrap());
+            ExprKind::WhileBlock(x, y) => {
+                let loop_start = output.len();
+                let condition_id = expr_to_id(*x, variables, consts, ctx, &mut output)?;
                 let mut priv_vars = variables.clone();
-                let cond_code = parser_to_instr_set(y.into_vec(), &mut priv_vars, consts);
-                let len = (cond_code.len() + 2) as u16;
-                output.push(Instr::Cmp(condition_id, len));
+                ctx.loops += 1;
+                let cond_code = parser_to_instr_set(y.into_vec(), &mut priv_vars, consts, ctx)?;
+                ctx.loops -= 1;
+                output.push(Instr::Cmp(
+                    condition_id,
+                    jump_size(cond_code.len() + 2, ctx)?,
+                ));
+                let code_start = output.len();
                 output.extend(cond_code);
-                output.push(Instr::Jmp(len, true));
+                // go back to the start of the condition
+                output.push(Instr::Jmp(jump_size(output.len() - loop_start, ctx)?, true));
+                patch_loop_jumps(&mut output, code_start, loop_start, ctx)?;
             }
-            Expr::VarDeclare(x, y) => {
-                let val = *y;
-                if let Expr::Num(data) = val {
-                    consts.push(Data::Number(data));
-                    variables.push((x, (consts.len() - 1) as u16));
-                } else if let Expr::String(data) = val {
-                    consts.push(Data::String(Intern::from(data)));
-                    variables.push((x, (consts.len() - 1) as u16));
-                } else if let Expr::Bool(data) = val {
-                    consts.push(Data::Bool(data));
-                    variables.push((x, (consts.len() - 1) as u16));
-                } else if let Expr::Var(name) = val {
-                    consts.push(Data::Null);
-                    variables.push((x, (consts.len() - 1) as u16));
-                    if let Some((_, var_id)) = variables.iter().find(|(x, _)| &name == x) {
-                        output.push(Instr::Mov(*var_id, (consts.len() - 1) as u16));
-                    } else {
-                        error!(format_args!("Unknown variable {}", name.red()));
+            ExprKind::ForLoop(name, iterable, code) => {
+                let mut priv_vars = variables.clone();
+                let iterable = *iterable;
+                let head = match iterable.kind {
+                    // ranges are iterated over directly, without creating them
+                    ExprKind::FunctionCall(func, args) if func == "range" => {
+                        let base = range_to_registers(args, variables, consts, ctx, &mut output)?;
+                        let var = new_register(consts, ctx)?;
+                        priv_vars.push((name, var));
+                        output.push(Instr::ForRange(base, var, 0));
+                        output.len() - 1
                     }
-                } else {
-                    consts.push(Data::Null);
-                    let len = (consts.len() - 1) as u16;
-                    variables.push((x, len));
-                    let mut val = parser_to_instr_set(vec![val], variables, consts);
-                    move_to_id(&mut val, len);
-                    output.extend(val);
+                    kind => {
+                        let iterable = Expr::new(kind, iterable.span);
+                        let iter = new_register(consts, ctx)?;
+                        let state = new_register(consts, ctx)?;
+                        let var = new_register(consts, ctx)?;
+                        priv_vars.push((name, var));
+                        expr_to_dest(iterable, iter, variables, consts, ctx, &mut output)?;
+                        let zero = ctx.expr(ExprKind::Int(0));
+                        let zero = expr_to_id(zero, variables, consts, ctx, &mut output)?;
+                        output.push(Instr::Mov(zero, state));
+                        output.push(Instr::ForIn(iter, state, 0));
+                        output.len() - 1
+                    }
+                };
+                ctx.loops += 1;
+                let code = parser_to_instr_set(code.into_vec(), &mut priv_vars, consts, ctx)?;
+                ctx.loops -= 1;
+                output.extend(code);
+                output.push(Instr::Jmp(jump_size(output.len() - head, ctx)?, true));
+                // `continue` goes back to the loop's head, which moves to the next element
+                patch_loop_jumps(&mut output, head + 1, head, ctx)?;
+                let size = jump_size(output.len() - head, ctx)?;
+                match &mut output[head] {
+                    Instr::ForRange(_, _, x) | Instr::ForIn(_, _, x) => *x = size,
+                    _ => unreachable!(),
                 }
             }
-            Expr::VarAssign(x, y) => {
-                let id = variables
-                    .iter()
-                    .find(|(w, _)| w == &x)
-                    .unwrap_or_else(|| panic!("Unknown variable {x}"))
-                    .1;
+            ExprKind::VarDeclare(x, y) => {
                 let val = *y;
-                if let Expr::Num(data) = val {
-                    consts.push(Data::Number(data));
-                    output.push(Instr::Mov((consts.len() - 1) as u16, id))
-                } else if let Expr::String(data) = val {
-                    consts.push(Data::String(Intern::from(data)));
-                    output.push(Instr::Mov((consts.len() - 1) as u16, id))
-                } else if let Expr::Bool(data) = val {
-                    consts.push(Data::Bool(data));
-                    output.push(Instr::Mov((consts.len() - 1) as u16, id))
-                } else if let Expr::Var(name) = val {
-                    if let Some((_, var_id)) = variables.iter().find(|(x, _)| &name == x) {
-                        output.push(Instr::Mov(*var_id, id));
-                    } else {
-                        error!(format_args!("Unknown variable {}", name.red()));
-                    }
+                // inside loops, the variable has to be reset at every iteration
+                if let Some(value) = literal(&val.kind).filter(|_| ctx.loops == 0) {
+                    consts.push(value);
+                    variables.push((x, register_id(consts.len() - 1, ctx)?));
                 } else {
-                    let mut value = parser_to_instr_set(vec![val], variables, consts);
-                    move_to_id(&mut value, id);
-                    output.extend(value);
+                    let id = new_register(consts, ctx)?;
+                    // the value is compiled first, so that it can refer to a variable being shadowed
+                    expr_to_dest(val, id, variables, consts, ctx, &mut output)?;
+                    variables.push((x, id));
                 }
             }
-            Expr::FunctionCall(x, args) => match x.as_str() {
-                "print" => {
-                    for arg in args {
-                        let (id, _) = get_id(arg, variables, consts);
-                        output.push(Instr::Print(id));
+            ExprKind::VarAssign(x, y) => {
+                let id = get_var_id(&x, variables, ctx)?;
+                expr_to_dest(*y, id, variables, consts, ctx, &mut output)?;
+            }
+            ExprKind::IndexAssign(target, index, value) => {
+                let target = expr_to_id(*target, variables, consts, ctx, &mut output)?;
+                let index = expr_to_id(*index, variables, consts, ctx, &mut output)?;
+                let value = expr_to_id(*value, variables, consts, ctx, &mut output)?;
+                output.push(Instr::SetIndex(target, index, value));
+            }
+            ExprKind::CompoundAssign(x, op, y) => {
+                let id = get_var_id(&x, variables, ctx)?;
+                let value = expr_to_id(*y, variables, consts, ctx, &mut output)?;
+                // the variable's register is updated in place
+                handle_ops!(output, id, value, id, op);
+            }
+            ExprKind::FunctionCall(x, args) => {
+                call_to_instr(&x, args, variables, consts, ctx, &mut output)?;
+            }
+            ExprKind::FunctionDecl(name, params, code) => {
+                let qualified = ctx.qualify(&name);
+                let id = ctx
+                    .functions
+                    .iter()
+                    .rposition(|x| x.name == qualified)
+                    .unwrap();
+                // the function is only run when called
+                let skip = output.len();
+                output.push(Instr::Jmp(0, false));
+                let lo = register_id(consts.len(), ctx)?;
+                // functions only see their parameters
+                let mut func_vars: Vec<(String, u16)> = Vec::new();
+                for param in params {
+                    if func_vars.iter().any(|(x, _)| *x == param) {
+                        fail!(format_args!(
+                            "Function {} has several parameters named {}{}",
+                            name.red(),
+                            param.red(),
+                            ctx.at()
+                        ));
                     }
+                    func_vars.push((param, new_register(consts, ctx)?));
                 }
-                unknown => {
-                    error!(format_args!("Unknown function {}", unknown.red()));
+                let was_in_function = std::mem::replace(&mut ctx.in_function, true);
+                let code = parser_to_instr_set(code.into_vec(), &mut func_vars, consts, ctx)?;
+                ctx.in_function = was_in_function;
+                ctx.functions[id].start = output.len();
+                output.extend(code);
+                // functions that don't return anything return null
+                let null = new_register(consts, ctx)?;
+                output.push(Instr::Ret(null));
+                ctx.functions[id].registers = lo..register_id(consts.len(), ctx)?;
+                output[skip] = Instr::Jmp(jump_size(output.len() - skip, ctx)?, false);
+            }
+            kind @ (ExprKind::Break | ExprKind::Continue) => {
+                if ctx.loops == 0 {
+                    let name = if kind == ExprKind::Break {
+                        "break"
+                    } else {
+                        "continue"
+                    };
+                    fail!(format_args!(
+                        "{name} can only be used inside a loop{}",
+                        ctx.at()
+                    ));
                 }
-            },
-            Expr::Op(left, right) => {
-                fn remove_priority(
-                    x: Expr,
-                    variables: &mut Vec<(String, u16)>,
-                    consts: &mut Vec<Data>,
-                ) -> Vec<Expr> {
-                    match x {
-                        Expr::Op(_, _) => process_op(x, variables, consts),
-                        Expr::Priority(x) => {
-                            let mut output: Vec<Expr> = vec![];
-                            output.push(Expr::LPAREN);
-                            output.extend(remove_priority(*x, variables, consts));
-                            output.push(Expr::RPAREN);
-                            output
-                        }
-                        _ => vec![x],
-                    }
-                }
-                fn process_op(
-                    op: Expr,
-                    variables: &mut Vec<(String, u16)>,
-                    consts: &mut Vec<Data>,
-                ) -> Vec<Expr> {
-                    let mut operation: Vec<Expr> = vec![];
-                    if let Expr::Op(left, mut right) = op {
-                        operation.extend(remove_priority(*left, variables, consts));
-                        for x in right.iter_mut() {
-                            let val = *x.1.clone();
-                            operation.extend(remove_priority(Expr::Opcode(x.0), variables, consts));
-                            if matches!(val, Expr::Op(_, _)) {
-                                operation.extend(process_op(val, variables, consts));
-                            } else {
-                                operation.extend(remove_priority(val, variables, consts));
-                            }
-                        }
-                    }
-                    operation
+                output.push(if kind == ExprKind::Break {
+                    BREAK
+                } else {
+                    CONTINUE
+                });
+            }
+            // imported files are linked before the code importing them
+            ExprKind::Import(_) => {}
+            ExprKind::Return(value) => {
+                if !ctx.in_function {
+                    fail!(format_args!(
+                        "return can only be used inside a function{}",
+                        ctx.at()
+                    ));
                 }
+                let id = match value {
+                    Some(value) => expr_to_id(*value, variables, consts, ctx, &mut output)?,
+                    None => new_register(consts, ctx)?,
+                };
+                output.push(Instr::Ret(id));
+            }
+            kind @ (ExprKind::Op(_, _)
+            | ExprKind::Var(_)
+            | ExprKind::Priority(_)
+            | ExprKind::Neg(_)
+            | ExprKind::Not(_)
+            | ExprKind::MethodCall(_, _, _)
+            | ExprKind::Array(_)
+            | ExprKind::Index(_, _)
+            | ExprKind::Slice(_, _, _)) => {
+                expr_to_id(Expr::new(kind, span), variables, consts, ctx, &mut output)?;
+            }
+            _ => {
+                fail!(format_args!("Not implemented{}", ctx.at()));
+            }
+        }
+    }
+    enter_span(outer, ctx, &mut output);
 
-                let temp_op: Vec<Expr> = process_op(Expr::Op(left, right), variables, consts);
-                print!("TEMPOP {temp_op:?}");
-                let op = op_to_rpn(temp_op);
-                print!("OP {op:?}");
+    Ok(output)
+}
 
-                let mut item_stack: Vec<Expr> = Vec::new();
-                let mut final_stack: Vec<Instr> = Vec::new();
-                for x in op {
-                    if let Expr::Opcode(op) = x {
-                        if !final_stack.is_empty() {
-                            let old_id = get_tgt_id(*final_stack.last().unwrap());
-                            let new = item_stack.pop().unwrap();
+fn read_input(input: &Input) -> Vec<u8> {
+    match input {
+        Input::File(path) => fs::read(path).unwrap_or_else(|_| {
+            error!(format_args!("Unable to read file '{}'", input.name().red()));
+        }),
+        Input::Stdin => {
+            let mut contents = Vec::new();
+            io::stdin().read_to_end(&mut contents).unwrap_or_else(|_| {
+                error!("Unable to read from the standard input");
+            });
+            contents
+        }
+    }
+}
 
-                            let (new_v, _) = get_id(new, variables, consts);
-                            consts.push(Data::Null);
-                            let x = old_id;
-                            let y = new_v;
-                            let z = consts.len() - 1;
-                            handle_ops!(final_stack, x, y, z as u16, op)
-                        } else {
-                            let last = item_stack.pop().unwrap();
-                            let first = item_stack.pop().unwrap();
-
-                            let (first_v, _) = get_id(first, variables, consts);
-                            let (second_v, _) = get_id(last, variables, consts);
-                            let x = first_v;
-                            let y = second_v;
-                            consts.push(Data::Null);
-                            let z = consts.len() - 1;
-                            handle_ops!(final_stack, x, y, z as u16, op);
-                        }
-                    } else {
-                        item_stack.push(x);
-                    }
-                }
-                output.extend(final_stack);
-            }
-            _ => {
-                error!("Not implemented");
+fn to_source(bytes: Vec<u8>, input: &Input) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|_| {
+        error!(format_args!("'{}' is not valid UTF-8", input.name().red()));
+    })
+}
+
+type SyntaxError = ParseError<usize, String, SourceError>;
+
+/// Parses source code, comments and macros included. Parsing goes on after a syntax error, so
+/// that all of them are returned.
+fn parse(src: &str) -> Result<Box<[Expr]>, Vec<SyntaxError>> {
+    let expanded = preprocess::expand_macros(src).map_err(|(location, message)| {
+        vec![ParseError::User {
+            error: SourceError { location, message },
+        }]
+    })?;
+    // positions (of the errors and of the expressions) are given in the source, not in the
+    // expanded code
+    let position = |x: usize| expanded.source_position(x);
+    let tokens = lexer::Lexer::new(&expanded.code).map(|token| match token {
+        Ok((start, x, end)) => Ok((position(start), x, position(end))),
+        Err(e) => Err(SourceError {
+            location: position(e.location),
+            ..e
+        }),
+    });
+    let mut recovered = Vec::new();
+    let result = grammar::CodeParser::new().parse(&mut recovered, tokens);
+    let mut errors: Vec<SyntaxError> = recovered
+        .into_iter()
+        .map(|x| x.error.map_token(|x| x.to_string()))
+        .collect();
+    match result {
+        Ok(code) if errors.is_empty() => Ok(code),
+        Ok(_) => Err(errors),
+        Err(e) => {
+            errors.push(e.map_token(|x| x.to_string()));
+            Err(errors)
+        }
+    }
+}
+
+/// Readable list of the tokens expected by the parser
+fn expected_tokens(expected: &[String]) -> String {
+    let mut names: Vec<String> = Vec::new();
+    for x in expected {
+        let name = match x.trim_matches('"') {
+            "identifier" => String::from("a name"),
+            "integer" | "float" => String::from("a number"),
+            "string" => String::from("a string"),
+            "path" => String::from("a path"),
+            token => format!("`{token}`"),
+        };
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    match names.as_slice() {
+        [] => String::new(),
+        [x] => format!(", expected {x}"),
+        [rest @ .., last] => format!(", expected one of {} or {last}", rest.join(", ")),
+    }
+}
+
+/// Describes a syntax error, with its line and column in `source`
+fn describe_syntax_error(e: &SyntaxError, source: &SourceFile) -> String {
+    match e {
+        ParseError::InvalidToken { location } => {
+            format!("Invalid token at {}", source.describe(*location))
+        }
+        ParseError::UnrecognizedEof { location, expected } => format!(
+            "Unexpected end of file at {}{}",
+            source.describe(*location),
+            expected_tokens(expected)
+        ),
+        ParseError::UnrecognizedToken {
+            token: (start, token, _),
+            expected,
+        } => format!(
+            "Unexpected `{token}` at {}{}",
+            source.describe(*start),
+            expected_tokens(expected)
+        ),
+        ParseError::ExtraToken {
+            token: (start, token, _),
+        } => format!("Unexpected `{token}` at {}", source.describe(*start)),
+        ParseError::User { error } => {
+            format!("{} at {}", error.message, source.describe(error.location))
+        }
+    }
+}
+
+/// Error listing every syntax error of `source`
+fn syntax_errors(errors: &[SyntaxError], source: &SourceFile) -> Error {
+    let messages: Vec<String> = errors
+        .iter()
+        .map(|e| concat_string!("Syntax error: ", describe_syntax_error(e, source)))
+        .collect();
+    Error::new(messages.join("\n"), None)
+}
+
+/// Compiles a script and the files it imports.
+///
+/// Returns the program and the imported files with the hash of their contents.
+fn compile(contents: &str, input: &Input) -> (Program, Vec<(PathBuf, String)>) {
+    let source = SourceFile::new(input.name(), contents);
+    let parsed = parse(contents).unwrap_or_else(|e| syntax_errors(&e, &source).exit());
+    log!("{parsed:?}");
+    let path = match input {
+        Input::File(path) => Some(path.as_path()),
+        Input::Stdin => None,
+    };
+    let modules = imports::load(parsed, source, path).unwrap_or_else(|e| e.exit());
+    let names: Vec<String> = modules.iter().map(|x| x.name.clone()).collect();
+    let mut variables: Vec<(String, u16)> = Vec::new();
+    let mut consts: Vec<Data> = Vec::new();
+    let mut ctx = Context::default();
+    let mut instructions: Vec<Instr> = Vec::new();
+    let mut debug = DebugInfo::default();
+    // global variables of each file, visible in the files importing it
+    let mut globals: Vec<Vec<(String, u16)>> = Vec::new();
+    let mut files = Vec::new();
+    // the files are linked one after the other, the main file last
+    for module in modules {
+        variables.clear();
+        for &i in &module.imports {
+            for (name, id) in &globals[i] {
+                variables.push((name.clone(), *id));
+                variables.push((concat_string!(names[i], "::", name), *id));
             }
         }
+        let imported = variables.len();
+        ctx.module = module.name;
+        ctx.imports = module.imports.iter().map(|&i| names[i].clone()).collect();
+        ctx.source = module.source;
+        let start = instructions.len();
+        let declared = ctx.functions.len();
+        let code = parser_to_instr_set(module.code, &mut variables, &mut consts, &mut ctx)
+            .unwrap_or_else(|e| e.exit());
+        debug.add(&ctx.source, &code.spans);
+        instructions.extend(code.instrs);
+        for function in &mut ctx.functions[declared..] {
+            function.start += start;
+        }
+        globals.push(variables[imported..].to_vec());
+        files.extend(module.file);
     }
+    // like in the older versions of the language, `main` is run after the top-level code
+    if let Some(main) = ctx.functions.iter().position(|x| x.name == "main") {
+        if ctx.functions[main].params != 0 {
+            error!("Function main cannot take any parameters");
+        }
+        let dest = new_register(&mut consts, &ctx).unwrap_or_else(|e| e.exit());
+        instructions.push(Instr::Call(main as u16, 0, dest));
+    }
+    log!("INSTR OUT {instructions:?}");
+    log!("CONSTS ARE {consts:?}");
+    log!("VARS ARE {variables:?}");
+    let program = Program {
+        instructions,
+        consts,
+        variables,
+        functions: ctx.functions,
+        debug,
+    };
+    (program, files)
+}
 
-    output
+/// Compiles the input, or loads it directly if it is already compiled or cached
+fn load_program(input: &Input, use_cache: bool) -> Program {
+    let bytes = read_input(input);
+    if bytecode::is_bytecode(&bytes) {
+        return bytecode::decode(&bytes).unwrap_or_else(|e| {
+            error!(
+                format_args!("'{}' {e}", input.name().red()),
+                "Compile it again with `compute build`"
+            );
+        });
+    }
+    let contents = to_source(bytes, input);
+    if matches!(input, Input::File(path) if path.extension().is_some_and(|x| x == asm::EXTENSION)) {
+        return asm::assemble(&contents, &input.name());
+    }
+    if !use_cache {
+        return compile(&contents, input).0;
+    }
+    if let Some(program) = cache::load(&contents, input) {
+        log!("LOADED FROM CACHE");
+        return program;
+    }
+    let (program, imports) = compile(&contents, input);
+    cache::store(&contents, input, &program, imports);
+    program
 }
 
 // Live long and prosper
@@ -707,48 +2304,414 @@ fn main() {
     // dbg!(size_of::<Data>());
     // dbg!(size_of::<Expr>());
 
-    let now = Instant::now();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() {
+        cli::print_banner();
+        return;
+    }
+    let cli = cli::parse_args(&args).unwrap_or_else(|e| cli::usage_error(&e));
 
-    print!("READ FILE");
-    let contents = fs::read_to_string("test.spock").unwrap();
-    print!("PARSE");
-    let parsed = grammar::CodeParser::new().parse(&contents).unwrap();
-    print!("{parsed:?}");
+    if cli.clear_cache {
+        cache::clear();
+    }
+    LOG.store(cli.log, Ordering::Relaxed);
 
-    let mut variables: Vec<(String, u16)> = Vec::new();
-    let mut consts: Vec<Data> = Vec::new();
-    let instructions = parser_to_instr_set(parsed.into_vec(), &mut variables, &mut consts);
-    println!("INSTR OUT {instructions:?}");
-    println!("CONSTS ARE {consts:?}");
-    print!("VARS ARE {variables:?}");
-    println!("Parsed in {:.2?}", now.elapsed());
-
-    let now = Instant::now();
-    // let instructions: Vec<Instr> = vec![
-    //     Instr::Inf(0, 3, 6),
-    //     Instr::Cmp(6, 7),
-    //     Instr::Add(0, 5, 0),
-    //     Instr::Mul(1, 2, 1),
-    //     Instr::Sup(1, 4, 7),
-    //     Instr::Cmp(7, 1),
-    //     Instr::Mod(1, 4, 1),
-    //     Instr::Jmp(7, true),
-    //     Instr::Print(1),
-    // ];
-    // let mut consts: Vec<Data> = vec![
-    //     // count
-    //     Data::Number(0.0),
-    //     // result
-    //     Data::Number(1.0),
-    //     // nums
-    //     Data::Number(2.0),       // -> 2
-    //     Data::Number(1000000.0), // -> 3
-    //     Data::Number(1000000.0), // -> 4
-    //     Data::Number(1.0),       // -> 5
-    //     Data::Bool(false),       // -> 6
-    //     Data::Bool(false),       // -> 7
-    // ];
-    execute(&instructions, &mut consts);
-    print!("CONSTS are {consts:?}");
-    println!("EXEC TIME {:.2?}", now.elapsed());
+    match cli.command {
+        Command::Help => cli::print_help(),
+        Command::Version => println!("compute {}", env!("CARGO_PKG_VERSION")),
+        Command::Nothing => {}
+        Command::Repl => repl::repl(&cli.limits),
+        Command::Check(input) => {
+            let now = Instant::now();
+            load_program(&input, !cli.no_cache);
+            if cli.timings {
+                eprintln!("Parsed in {:.2?}", now.elapsed());
+            }
+        }
+        Command::Disasm(input) => {
+            let program = load_program(&input, !cli.no_cache);
+            print!(
+                "{}",
+                disasm::disassemble(
+                    &program.instructions,
+                    &program.consts,
+                    &program.variables,
+                    &program.functions
+                )
+            );
+        }
+        Command::Build(input, output) => {
+            let now = Instant::now();
+            let program = load_program(&input, !cli.no_cache);
+            let output = output.unwrap_or_else(|| match &input {
+                Input::File(path) => path.with_extension(bytecode::EXTENSION),
+                Input::Stdin => unreachable!(),
+            });
+            fs::write(&output, bytecode::encode(&program)).unwrap_or_else(|_| {
+                error!(format_args!(
+                    "Unable to write to '{}'",
+                    output.display().to_string().red()
+                ));
+            });
+            if cli.timings {
+                eprintln!("Built in {:.2?}", now.elapsed());
+            }
+        }
+        Command::Run(ref input) | Command::Asm(ref input) => {
+            let is_asm = matches!(cli.command, Command::Asm(_));
+            SCRIPT_ARGS.set(cli.script_args.into_boxed_slice()).unwrap();
+
+            let now = Instant::now();
+            let Program {
+                instructions,
+                mut consts,
+                functions,
+                debug,
+                ..
+            } = if is_asm {
+                let contents = to_source(read_input(input), input);
+                asm::assemble(&contents, &input.name())
+            } else {
+                load_program(input, !cli.no_cache)
+            };
+            if cli.timings {
+                eprintln!("Parsed in {:.2?}", now.elapsed());
+            }
+
+            let now = Instant::now();
+            if let Err(e) = execute(
+                &instructions,
+                0,
+                &mut consts,
+                &mut Heap::default(),
+                &functions,
+                &debug,
+                &cli.limits,
+            ) {
+                io::stdout().flush().unwrap();
+                e.report();
+                std::process::exit(match e {
+                    RuntimeError::Error(_) => 1,
+                    RuntimeError::Limit(_) => limits::EXIT_LIMIT,
+                });
+            }
+            log!("CONSTS are {consts:?}");
+            if cli.timings {
+                eprintln!("Executed in {:.2?}", now.elapsed());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &str = "test.spock";
+
+    fn compile_source(src: &str) -> Result<Program, Error> {
+        let parsed = parse(src).unwrap();
+        let mut ctx = Context {
+            source: SourceFile::new(String::from(NAME), src),
+            ..Context::default()
+        };
+        let (mut variables, mut consts) = (Vec::new(), Vec::new());
+        let code = parser_to_instr_set(parsed.into_vec(), &mut variables, &mut consts, &mut ctx)?;
+        let mut debug = DebugInfo::default();
+        debug.add(&ctx.source, &code.spans);
+        Ok(Program {
+            instructions: code.instrs,
+            consts,
+            variables,
+            functions: ctx.functions,
+            debug,
+        })
+    }
+
+    // runs a script and returns the value of its variables
+    fn run(src: &str) -> Result<Vec<(String, Data)>, RuntimeError> {
+        let program = compile_source(src)?;
+        let mut consts = program.consts.clone();
+        execute(
+            &program.instructions,
+            0,
+            &mut consts,
+            &mut Heap::default(),
+            &program.functions,
+            &program.debug,
+            &Limits::default(),
+        )?;
+        Ok(program
+            .variables
+            .into_iter()
+            .map(|(name, id)| (name, consts[id as usize]))
+            .collect())
+    }
+
+    fn value(variables: &[(String, Data)], name: &str) -> Data {
+        variables.iter().find(|(x, _)| x == name).unwrap().1
+    }
+
+    fn string(x: &str) -> Data {
+        Data::String(Intern::from_ref(x))
+    }
+
+    fn syntax_errors(src: &str) -> Vec<String> {
+        let source = SourceFile::new(String::from(NAME), src);
+        parse(src)
+            .unwrap_err()
+            .iter()
+            .map(|e| describe_syntax_error(e, &source))
+            .collect()
+    }
+
+    fn error_message(src: &str) -> String {
+        match run(src).unwrap_err() {
+            RuntimeError::Error(e) => e.message,
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn for_in_loops() {
+        let variables = run(r#"
+            let total = 0;
+            for x in [1, 2, 3] { total += x; }
+            let text = "";
+            for c in "héllo" { text = c + text; }
+            let down = 0;
+            for i in range(10, 0, -3) { down = down * 100 + i; }
+            let count = 0;
+            for i in range(3) { for j in range(i) { count += 1; } }
+        "#)
+        .unwrap();
+        assert_eq!(value(&variables, "total"), Data::Number(6.0));
+        assert_eq!(value(&variables, "text"), string("olléh"));
+        assert_eq!(value(&variables, "down"), Data::Number(10070401.0));
+        assert_eq!(value(&variables, "count"), Data::Number(3.0));
+        assert_eq!(
+            error_message("let x = 1;\nfor c in x { }"),
+            "Cannot iterate over Number(1.0) at test.spock:2:1"
+        );
+    }
+
+    #[test]
+    fn recursive_calls() {
+        let variables = run("
+            func fib(n) { if n < 2 { return n; } return fib(n - 1) + fib(n - 2); }
+            func keep(n) { let local = n * 10; if n > 0 { keep(n - 1); } return local; }
+            func nothing() { let x = 1; }
+            let a = fib(15);
+            let b = keep(5);
+            let c = nothing();
+        ")
+        .unwrap();
+        assert_eq!(value(&variables, "a"), Data::Number(610.0));
+        // the registers of the caller are restored after each nested call
+        assert_eq!(value(&variables, "b"), Data::Number(50.0));
+        assert_eq!(value(&variables, "c"), Data::Null);
+        assert!(matches!(
+            run("func f() { return f(); }\nf();"),
+            Err(RuntimeError::Limit(_))
+        ));
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let variables = run("
+            let i = 0;
+            let found = -1;
+            let odd = 0;
+            while i < 10 {
+                i += 1;
+                if i % 2 == 0 {
+                    if i > 6 { found = i; break; }
+                    continue;
+                }
+                odd += 1;
+            }
+            let pairs = 0;
+            for x in range(5) {
+                for y in range(5) {
+                    if y > x { break; } else if y == 1 { continue; }
+                    pairs += 1;
+                }
+            }
+        ")
+        .unwrap();
+        assert_eq!(value(&variables, "found"), Data::Number(8.0));
+        assert_eq!(value(&variables, "odd"), Data::Number(4.0));
+        // only the innermost loop is left or continued
+        assert_eq!(value(&variables, "pairs"), Data::Number(11.0));
+        assert_eq!(
+            compile_source("let x = 1;\nif x > 0 { break; }")
+                .unwrap_err()
+                .message,
+            "break can only be used inside a loop at test.spock:2:12"
+        );
+    }
+
+    #[test]
+    fn compound_assignments() {
+        let variables = run(r#"
+            let n = 10;
+            n -= 4; n *= 2; n /= 3; n %= 3; n ^= 3;
+            let s = "a";
+            s += "b";
+            let total = 0;
+            for x in [1, 2, 3] { total += x * x; }
+        "#)
+        .unwrap();
+        assert_eq!(value(&variables, "n"), Data::Number(1.0));
+        assert_eq!(value(&variables, "s"), string("ab"));
+        assert_eq!(value(&variables, "total"), Data::Number(14.0));
+        // the variable's register is updated in place, without a temporary
+        let program = compile_source("let x = 1;\nlet y = 2;\nx += y;").unwrap();
+        assert_eq!(program.instructions, [Instr::Add(0, 1, 0)]);
+        assert_eq!(
+            compile_source("x += 1;").unwrap_err().message,
+            format!("Unknown variable {} at test.spock:1:1", "x".red())
+        );
+    }
+
+    #[test]
+    fn operands_overflow() {
+        // each declaration needs a register for the array and one for each of its elements
+        let array = format!("let v = [{}keep];\n", "keep, ".repeat(99));
+        let declarations = array.repeat(u16::MAX as usize / 100);
+        let error = compile_source(&format!("let keep = 1;\n{declarations}print(keep);"))
+            .unwrap_err()
+            .message;
+        assert!(error.starts_with("The program needs more than 65536 registers at test.spock:"));
+        let body = "    x += y;\n".repeat(u16::MAX as usize);
+        let error = compile_source(&format!("let x = 0;\nlet y = 1;\nwhile x < 1 {{\n{body}}}"))
+            .unwrap_err()
+            .message;
+        assert_eq!(
+            error,
+            "This code is too long to jump over (65537 instructions) at test.spock:3:1"
+        );
+    }
+
+    #[test]
+    fn unknown_methods() {
+        assert_eq!(
+            compile_source("let x = 1;\nlet y = x.foo();")
+                .unwrap_err()
+                .message,
+            format!("No type has a method {} at test.spock:2:9", "foo".red())
+        );
+        assert_eq!(
+            error_message("let x = 1;\nlet y = x.upper();"),
+            format!("Number has no method {} at test.spock:2:9", "upper".red())
+        );
+    }
+
+    #[test]
+    fn reports_every_syntax_error() {
+        let errors = syntax_errors("let x = ;\nlet y = 2 +;\nprint(y);\nlet z = (1;\n");
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("Unexpected `;` at test.spock:1:9, expected one of a name"));
+        assert!(errors[1].starts_with("Unexpected `;` at test.spock:2:12"));
+        assert_eq!(errors[2], "Unexpected `;` at test.spock:4:11, expected `)`");
+        // a missing `;` doesn't hide the errors after it
+        let errors = syntax_errors("let x = 1\nprint(x);\nlet = 4;\n");
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("Unexpected `print` at test.spock:2:1"));
+        assert_eq!(
+            errors[1],
+            "Unexpected `=` at test.spock:3:5, expected a name"
+        );
+    }
+
+    #[test]
+    fn syntax_errors_in_the_source() {
+        // located in the source even after a macro was expanded
+        let errors = syntax_errors("replace ONE -> 1\nlet x = ONE + $;");
+        assert_eq!(errors, ["Unexpected character `$` at test.spock:2:15"]);
+        assert_eq!(
+            syntax_errors("let x = 1; /* never closed"),
+            [format!(
+                "{} at test.spock:1:12",
+                lexer::UNTERMINATED_COMMENT
+            )]
+        );
+        assert_eq!(
+            syntax_errors("print(1);\n{\nreplace N -> 2\n}"),
+            ["Macros can only be declared outside of blocks at test.spock:3:1"]
+        );
+    }
+
+    #[test]
+    fn if_expressions() {
+        let variables = run(r#"
+            let n = 7;
+            let a = if 1 < 2 { "yes" } else { "no" };
+            let b = if n < 5 { 1 } else if n < 10 { 2 } else { 3 };
+            let c = if n > 0 { if n > 100 { "big" } else { let m = n * 2; m } } else { 0 };
+            func sign(x) { let s = if x < 0 { return -1; } else { 1 }; return s; }
+            let d = sign(-5);
+            let e = sign(5);
+            let f = 0;
+            if n == 7 { f = 1; } else if n == 8 { f = 2; } else { f = 3; }
+        "#)
+        .unwrap();
+        assert_eq!(value(&variables, "a"), string("yes"));
+        assert_eq!(value(&variables, "b"), Data::Number(2.0));
+        assert_eq!(value(&variables, "c"), Data::Number(14.0));
+        assert_eq!(value(&variables, "d"), Data::Number(-1.0));
+        assert_eq!(value(&variables, "e"), Data::Number(1.0));
+        assert_eq!(value(&variables, "f"), Data::Number(1.0));
+    }
+
+    #[test]
+    fn if_expression_errors() {
+        let error = |src: &str| compile_source(src).unwrap_err().message;
+        assert_eq!(
+            error("let x = if true { 1 };"),
+            "An if used as a value needs an else branch at test.spock:1:9"
+        );
+        assert_eq!(
+            error("let x = if true { 1 } else { let y = 2; };"),
+            "A branch of an if used as a value has no value at test.spock:1:30"
+        );
+        // a `;` turns the last expression into a statement
+        assert_eq!(
+            error("let x = if true { 1; } else { 2 };"),
+            "A branch of an if used as a value has no value at test.spock:1:19"
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        let variables = run("
+            let x = [1, [2, 3], 4];
+            x[-1] = x[1][0];
+            let first = x[0];
+            let rest = x[1:] == [[2, 3], 2];
+            let empty = x[5:] == [];
+            let text = \"héllo\"[1:3];
+        ")
+        .unwrap();
+        assert_eq!(value(&variables, "first"), Data::Number(1.0));
+        assert_eq!(value(&variables, "rest"), Data::Bool(true));
+        assert_eq!(value(&variables, "empty"), Data::Bool(true));
+        assert_eq!(value(&variables, "text"), string("él"));
+    }
+
+    #[test]
+    fn array_errors() {
+        assert_eq!(
+            error_message("let x = [1, 2];\nlet y = x[5];"),
+            "Index 5 is out of range for a length of 2 at test.spock:2:9"
+        );
+        assert_eq!(
+            error_message("let x = [1];\nx[-2] = 0;"),
+            "Index -2 is out of range for a length of 1 at test.spock:2:1"
+        );
+        assert_eq!(
+            error_message("let x = [1];\nlet y = x[0.5];"),
+            "Indices must be integers, got 0.5 at test.spock:2:9"
+        );
+    }
 }
@@ -0,0 +1,181 @@
+//! Methods called on values (`x.method(...)`), found from the type of the receiver when running
+
+use crate::arrays::Heap;
+use crate::limits::{Limits, Usage};
+use crate::{Data, Error, RuntimeError};
+use colored::Colorize;
+use internment::Intern;
+use std::fs;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    ToStr,
+    ToNum,
+    Len,
+    Upper,
+    Lower,
+    Trim,
+    Contains,
+    StartsWith,
+    EndsWith,
+    Replace,
+    Abs,
+    Round,
+    Floor,
+    Ceil,
+    Sqrt,
+    Push,
+    Pop,
+    Read,
+    Write,
+    Append,
+}
+
+/// Name and number of arguments of every method, `CallMethod` refers to them by index
+pub const METHODS: &[(&str, Method, usize)] = &[
+    ("toStr", Method::ToStr, 0),
+    ("toNum", Method::ToNum, 0),
+    ("len", Method::Len, 0),
+    ("upper", Method::Upper, 0),
+    ("lower", Method::Lower, 0),
+    ("trim", Method::Trim, 0),
+    ("contains", Method::Contains, 1),
+    ("startsWith", Method::StartsWith, 1),
+    ("endsWith", Method::EndsWith, 1),
+    ("replace", Method::Replace, 2),
+    ("abs", Method::Abs, 0),
+    ("round", Method::Round, 0),
+    ("floor", Method::Floor, 0),
+    ("ceil", Method::Ceil, 0),
+    ("sqrt", Method::Sqrt, 0),
+    ("push", Method::Push, 1),
+    ("pop", Method::Pop, 0),
+    ("read", Method::Read, 0),
+    ("write", Method::Write, 1),
+    ("append", Method::Append, 1),
+];
+
+/// Returns the index of a method and its number of arguments
+pub fn find(name: &str) -> Option<(u16, usize)> {
+    METHODS
+        .iter()
+        .position(|(x, _, _)| *x == name)
+        .map(|id| (id as u16, METHODS[id].2))
+}
+
+fn string_arg(method: &str, arg: Data) -> Result<Intern<String>, Error> {
+    match arg {
+        Data::String(str) => Ok(str),
+        other => {
+            fail!(format_args!(
+                "Method {} expected a String, got {}",
+                method.red(),
+                other.type_name()
+            ));
+        }
+    }
+}
+
+fn new_string(str: String, usage: &mut Usage, limits: &Limits) -> Result<Data, RuntimeError> {
+    usage.count_string(str.len(), limits)?;
+    Ok(Data::String(Intern::from(str)))
+}
+
+/// Calls a method, `args` starts with its arguments
+pub fn call(
+    method: u16,
+    receiver: Data,
+    args: &[Data],
+    heap: &mut Heap,
+    usage: &mut Usage,
+    limits: &Limits,
+) -> Result<Data, RuntimeError> {
+    let (name, method, _) = METHODS[method as usize];
+    let result = match (method, receiver) {
+        (Method::ToStr, Data::String(_)) => receiver,
+        (Method::ToStr, other) => new_string(heap.show(other).to_string(), usage, limits)?,
+
+        (Method::ToNum, Data::String(str)) => match str.trim().parse::<f64>() {
+            Ok(num) => Data::Number(num),
+            Err(_) => {
+                fail!(format_args!(
+                    "Cannot convert {:?} to a number",
+                    str.as_str()
+                ));
+            }
+        },
+        (Method::Len, Data::String(str)) => Data::Number(str.chars().count() as f64),
+        (Method::Upper, Data::String(str)) => new_string(str.to_uppercase(), usage, limits)?,
+        (Method::Lower, Data::String(str)) => new_string(str.to_lowercase(), usage, limits)?,
+        (Method::Trim, Data::String(str)) => Data::String(Intern::from_ref(str.trim())),
+        (Method::Contains, Data::String(str)) => {
+            Data::Bool(str.contains(string_arg(name, args[0])?.as_str()))
+        }
+        (Method::StartsWith, Data::String(str)) => {
+            Data::Bool(str.starts_with(string_arg(name, args[0])?.as_str()))
+        }
+        (Method::EndsWith, Data::String(str)) => {
+            Data::Bool(str.ends_with(string_arg(name, args[0])?.as_str()))
+        }
+        (Method::Replace, Data::String(str)) => {
+            let from = string_arg(name, args[0])?;
+            let to = string_arg(name, args[1])?;
+            new_string(str.replace(from.as_str(), &to), usage, limits)?
+        }
+
+        (Method::Len, Data::Array(array)) => Data::Number(heap.get(array).len() as f64),
+        (Method::Contains, Data::Array(array)) => {
+            Data::Bool(heap.get(array).iter().any(|x| heap.equal(*x, args[0])))
+        }
+        (Method::Push, Data::Array(array)) => {
+            usage.count_elements(1, limits)?;
+            heap.get_mut(array).push(args[0]);
+            Data::Null
+        }
+        (Method::Pop, Data::Array(array)) => {
+            let Some(x) = heap.get_mut(array).pop() else {
+                fail!("Cannot pop from an empty array");
+            };
+            x
+        }
+
+        (Method::Read, Data::File(path)) => {
+            let Ok(contents) = fs::read_to_string(path.as_str()) else {
+                fail!(format_args!("Unable to read file '{}'", path.red()));
+            };
+            new_string(contents, usage, limits)?
+        }
+        (Method::Write | Method::Append, Data::File(path)) => {
+            let content = heap.show(args[0]).to_string();
+            let result = if method == Method::Append {
+                fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path.as_str())
+                    .and_then(|mut file| file.write_all(content.as_bytes()))
+            } else {
+                fs::write(path.as_str(), content)
+            };
+            if result.is_err() {
+                fail!(format_args!("Unable to write to file '{}'", path.red()));
+            }
+            Data::Null
+        }
+
+        (Method::Abs, Data::Number(num)) => Data::Number(num.abs()),
+        (Method::Round, Data::Number(num)) => Data::Number(num.round()),
+        (Method::Floor, Data::Number(num)) => Data::Number(num.floor()),
+        (Method::Ceil, Data::Number(num)) => Data::Number(num.ceil()),
+        (Method::Sqrt, Data::Number(num)) => Data::Number(num.sqrt()),
+
+        (_, other) => {
+            fail!(format_args!(
+                "{} has no method {}",
+                other.type_name(),
+                name.red()
+            ));
+        }
+    };
+    Ok(result)
+}
@@ -0,0 +1,226 @@
+use crate::lexer::{Lexer, Tok};
+
+/// Returns the position right after the string literal starting at `start`, or `None` if it is
+/// never closed
+pub fn string_end(bytes: &[u8], start: usize) -> Option<usize> {
+    // in `r"..."`, backslashes don't escape anything
+    let is_raw = start > 0
+        && bytes[start - 1] == b'r'
+        && !(start > 1 && (bytes[start - 2].is_ascii_alphanumeric() || bytes[start - 2] == b'_'));
+    let quotes = if bytes[start..].starts_with(b"\"\"\"") {
+        3
+    } else {
+        1
+    };
+    let mut i = start + quotes;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if !is_raw => i += 2,
+            b'"' if bytes[i..].starts_with(&b"\"\"\""[..quotes]) => return Some(i + quotes),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+pub fn is_identifier_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+pub fn is_identifier(name: &str) -> bool {
+    name.bytes().next().is_some_and(is_identifier_start)
+        && name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_')
+}
+
+/// Source code whose `replace` macros were expanded
+pub struct Expanded {
+    pub code: String,
+    // position in `code`, length in `code`, position in the source, length in the source
+    replacements: Vec<(usize, usize, usize, usize)>,
+}
+
+impl Expanded {
+    /// Finds the position in the source matching a position in the expanded code, positions
+    /// inside an expanded macro map to the name of the macro
+    pub fn source_position(&self, position: usize) -> usize {
+        match self.replacements.iter().rev().find(|x| x.0 <= position) {
+            None => position,
+            Some(&(code, len, source, _)) if position < code + len => source,
+            Some(&(code, len, source, source_len)) => source + source_len + position - code - len,
+        }
+    }
+}
+
+/// Expands the `replace name->value` macros, declared on their own line outside of any block:
+/// every `name` identifier of the code following the declaration becomes `value`, strings and
+/// longer identifiers are left untouched.
+///
+/// Returns the position of an invalid macro and what is wrong with it as an error.
+pub fn expand_macros(code: &str) -> Result<Expanded, (usize, String)> {
+    // the code after an invalid token is left as is, the parser reports the error
+    let tokens: Vec<(usize, Tok, usize)> = Lexer::new(code).map_while(Result::ok).collect();
+    // name -- value -- end of the declaration
+    let mut macros: Vec<(&str, &str, usize)> = Vec::new();
+    // code declaring macros
+    let mut directives: Vec<(usize, usize)> = Vec::new();
+    // end of the previous token
+    let mut previous = 0;
+    // number of blocks the token is in
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < tokens.len() {
+        let (start, ref token, end) = tokens[i];
+        let first_of_line = i == 0 || code[previous..start].contains('\n');
+        previous = end;
+        i += 1;
+        match token {
+            Tok::LBrace => depth += 1,
+            Tok::RBrace => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if *token != Tok::Ident(String::from("replace"))
+            || !first_of_line
+            || !code
+                .as_bytes()
+                .get(end)
+                .is_some_and(u8::is_ascii_whitespace)
+        {
+            continue;
+        }
+        let line_end = code[end..].find('\n').map_or(code.len(), |x| end + x);
+        let count = tokens[i..].iter().take_while(|x| x.0 < line_end).count();
+        let line = &tokens[i..i + count];
+        let Some(arrow) = line
+            .windows(2)
+            .position(|x| x[0].1 == Tok::Minus && x[1].1 == Tok::Sup && x[0].2 == x[1].0)
+        else {
+            continue;
+        };
+        if depth > 0 {
+            return Err((
+                start,
+                String::from("Macros can only be declared outside of blocks"),
+            ));
+        }
+        let text = |x: &[(usize, Tok, usize)]| match x {
+            [] => "",
+            [first, .., last] | [first @ last] => &code[first.0..last.2],
+        };
+        let name = text(&line[..arrow]);
+        if !matches!(line[..arrow], [(_, Tok::Ident(_), _)]) {
+            return Err((start, format!("Invalid macro name `{name}`")));
+        }
+        let value = match &line[arrow + 2..] {
+            [rest @ .., (_, Tok::Semicolon, _)] => rest,
+            value => value,
+        };
+        if value.is_empty() {
+            return Err((start, format!("Macro `{name}` has no value")));
+        }
+        if macros.iter().any(|(x, _, _)| *x == name) {
+            return Err((start, format!("Macro `{name}` is already defined")));
+        }
+        // a string of the value can go on after the end of the line
+        previous = line_end.max(line[count - 1].2);
+        macros.push((name, text(value), previous));
+        directives.push((start, previous));
+        i += count;
+    }
+
+    // the declarations are replaced by spaces, so that positions don't change
+    let mut blanked = code.to_string();
+    for &(start, end) in &directives {
+        blanked.replace_range(start..end, &" ".repeat(end - start));
+    }
+    let mut out = String::with_capacity(code.len());
+    let mut replacements = Vec::new();
+    let mut copied = 0;
+    for (start, token, end) in &tokens {
+        let Tok::Ident(name) = token else {
+            continue;
+        };
+        if directives.iter().any(|(x, y)| (*x..*y).contains(start)) {
+            continue;
+        }
+        let Some((_, value, _)) = macros
+            .iter()
+            .find(|(x, _, declared)| x == name && declared <= start)
+        else {
+            continue;
+        };
+        out.push_str(&blanked[copied..*start]);
+        replacements.push((out.len(), value.len(), *start, end - start));
+        out.push_str(value);
+        copied = *end;
+    }
+    out.push_str(&blanked[copied..]);
+    Ok(Expanded {
+        code: out,
+        replacements,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(code: &str) -> String {
+        expand_macros(code).unwrap().code
+    }
+
+    #[test]
+    fn replaces_identifiers_only() {
+        let code = expand("replace N -> 3\nprint(N, \"N\", N_2, r\"N\");");
+        assert_eq!(code.trim(), "print(3, \"N\", N_2, r\"N\");");
+    }
+
+    #[test]
+    fn applies_after_the_declaration() {
+        let code = expand("print(N);\nreplace N -> 3\nprint(N);");
+        assert_eq!(code.lines().next(), Some("print(N);"));
+        assert_eq!(code.lines().last(), Some("print(3);"));
+    }
+
+    #[test]
+    fn ignores_comments() {
+        let code = expand("replace N -> 1 + 2; // sum\n/* N */ print(N);");
+        assert_eq!(code.lines().last(), Some("/* N */ print(1 + 2);"));
+    }
+
+    #[test]
+    fn only_declared_outside_of_blocks() {
+        let code = "func f() {\n    replace N -> 3\n}";
+        let error = expand_macros(code).err().unwrap();
+        assert_eq!(error.0, code.find("replace").unwrap());
+        // not a declaration once it isn't at the start of a line
+        assert!(expand_macros("let replace = 1; replace - > 2;").is_ok());
+    }
+
+    #[test]
+    fn invalid_declarations() {
+        let error = |code: &str| expand_macros(code).err().unwrap().1;
+        assert_eq!(error("replace if -> 2"), "Invalid macro name `if`");
+        assert_eq!(error("replace N ->;"), "Macro `N` has no value");
+        assert_eq!(
+            error("replace N -> 1\nreplace N -> 2"),
+            "Macro `N` is already defined"
+        );
+    }
+
+    #[test]
+    fn maps_positions_to_the_source() {
+        let src = "replace LONG_NAME -> 1\nlet x = LONG_NAME + y;";
+        let expanded = expand_macros(src).unwrap();
+        let value = expanded.code.find("1 +").unwrap();
+        assert_eq!(
+            expanded.source_position(value),
+            src.rfind("LONG_NAME").unwrap()
+        );
+        // positions after the macro are shifted back by the difference of length
+        let y = expanded.code.find('y').unwrap();
+        assert_eq!(expanded.source_position(y), src.find('y').unwrap());
+        // positions before it don't change
+        let x = expanded.code.find('x').unwrap();
+        assert_eq!(expanded.source_position(x), src.find('x').unwrap());
+    }
+}
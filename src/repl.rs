@@ -0,0 +1,285 @@
+use crate::arrays::Heap;
+use crate::limits::Limits;
+use crate::span::{DebugInfo, SourceFile};
+use crate::{
+    call_to_instr, describe_syntax_error, disasm, enter_span, execute, expr_to_id, lexer, parse,
+    parser_to_instr_set, Context, Data, Expr, ExprKind, Instr, RuntimeError,
+};
+use colored::Colorize;
+use lalrpop_util::ParseError;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::path::PathBuf;
+
+const HELP: &str = "\
+:help     Show this message
+:vars     List the variables and their current value
+:instrs   List every instruction executed so far
+:reset    Forget all variables and instructions
+:quit     Exit the REPL (Ctrl-D works too)";
+
+/// What a line (or group of lines) typed by the user turned out to be
+enum Entry {
+    Code(Vec<Expr>),
+    // an `if`/`while` block, an expression,... that isn't finished yet
+    Incomplete,
+    // one message per syntax error
+    Invalid(Vec<String>),
+}
+
+struct Session {
+    variables: Vec<(String, u16)>,
+    consts: Vec<Data>,
+    heap: Heap,
+    instructions: Vec<Instr>,
+    debug: DebugInfo,
+    ctx: Context,
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".spock_history"))
+}
+
+fn parse_entry(src: &str) -> Entry {
+    match parse(src) {
+        Ok(code) if code.iter().any(|x| matches!(x.kind, ExprKind::Import(_))) => {
+            Entry::Invalid(vec![String::from("Imports are not supported in the REPL")])
+        }
+        Ok(code) => Entry::Code(code.into_vec()),
+        Err(e) if matches!(e.as_slice(), [ParseError::UnrecognizedEof { .. }]) => {
+            // allow bare expressions such as `x + 1` without the trailing semicolon,
+            // everything else that stopped at the end of the input is just unfinished
+            match parse(&format!("{src};")) {
+                Ok(code) => Entry::Code(code.into_vec()),
+                Err(_) => Entry::Incomplete,
+            }
+        }
+        // a block comment spanning several lines
+        Err(e)
+            if matches!(e.as_slice(), [ParseError::User { error }]
+                if error.message == lexer::UNTERMINATED_COMMENT) =>
+        {
+            Entry::Incomplete
+        }
+        Err(e) => {
+            let source = SourceFile::new(String::from("<repl>"), src);
+            Entry::Invalid(
+                e.iter()
+                    .map(|x| describe_syntax_error(x, &source))
+                    .collect(),
+            )
+        }
+    }
+}
+
+fn is_value(expr: &Expr) -> bool {
+    matches!(
+        expr.kind,
+        ExprKind::Num(_)
+            | ExprKind::Int(_)
+            | ExprKind::Bool(_)
+            | ExprKind::String(_)
+            | ExprKind::Var(_)
+            | ExprKind::Op(_, _)
+            | ExprKind::Priority(_)
+            | ExprKind::Neg(_)
+            | ExprKind::Not(_)
+            | ExprKind::FunctionCall(_, _)
+            | ExprKind::MethodCall(_, _, _)
+            | ExprKind::Array(_)
+            | ExprKind::Index(_, _)
+            | ExprKind::Slice(_, _, _)
+    )
+}
+
+fn show(value: Data, heap: &Heap) {
+    match value {
+        Data::String(str) => println!("{:?}", str.as_str()),
+        other => println!("{}", heap.show(other)),
+    }
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            variables: Vec::new(),
+            consts: Vec::new(),
+            heap: Heap::default(),
+            instructions: Vec::new(),
+            debug: DebugInfo::default(),
+            ctx: Context::default(),
+        }
+    }
+
+    /// Compiles and runs an entry. When it fails, the session goes back to its state before the
+    /// entry.
+    fn run(&mut self, code: Vec<Expr>, src: &str, limits: &Limits) {
+        let variables = self.variables.clone();
+        let consts = self.consts.clone();
+        let heap = self.heap.clone();
+        let instructions = self.instructions.len();
+        let functions = self.ctx.functions.len();
+        if let Err(e) = self.try_run(code, src, limits) {
+            e.report();
+            self.variables = variables;
+            self.consts = consts;
+            self.heap = heap;
+            self.instructions.truncate(instructions);
+            self.debug.locations.truncate(instructions);
+            self.ctx.functions.truncate(functions);
+            // the error may have stopped the compilation inside a loop or a function
+            self.ctx.loops = 0;
+            self.ctx.in_function = false;
+        }
+    }
+
+    fn try_run(
+        &mut self,
+        mut code: Vec<Expr>,
+        src: &str,
+        limits: &Limits,
+    ) -> Result<(), RuntimeError> {
+        let last = code.pop_if(|x| is_value(x));
+
+        self.ctx.source = SourceFile::new(String::from("<repl>"), src);
+        let start = self.instructions.len();
+        let declared = self.ctx.functions.len();
+        let mut new =
+            parser_to_instr_set(code, &mut self.variables, &mut self.consts, &mut self.ctx)?;
+        // the new functions start relative to the entry's code
+        for function in &mut self.ctx.functions[declared..] {
+            function.start += start;
+        }
+        // register holding the value to display, if the entry ends with an expression
+        let result = match last.map(|expr| (expr.kind, expr.span)) {
+            // functions such as `print` don't return anything
+            Some((ExprKind::FunctionCall(name, args), span)) => {
+                enter_span(span, &mut self.ctx, &mut new);
+                call_to_instr(
+                    &name,
+                    args,
+                    &mut self.variables,
+                    &mut self.consts,
+                    &mut self.ctx,
+                    &mut new,
+                )?
+            }
+            Some((kind, span)) => Some(expr_to_id(
+                Expr::new(kind, span),
+                &mut self.variables,
+                &mut self.consts,
+                &mut self.ctx,
+                &mut new,
+            )?),
+            None => None,
+        };
+        self.debug.add(&self.ctx.source, &new.spans);
+        self.instructions.extend(new.instrs);
+
+        execute(
+            &self.instructions,
+            start,
+            &mut self.consts,
+            &mut self.heap,
+            &self.ctx.functions,
+            &self.debug,
+            limits,
+        )?;
+        if let Some(id) = result {
+            show(self.consts[id as usize], &self.heap);
+        }
+        Ok(())
+    }
+
+    // returns false when the REPL should stop
+    fn command(&mut self, cmd: &str) -> bool {
+        match cmd {
+            "help" | "h" => println!("{HELP}"),
+            "vars" => {
+                for (name, id) in &self.variables {
+                    println!(
+                        "{} = {}",
+                        name.blue(),
+                        self.heap.show(self.consts[*id as usize])
+                    );
+                }
+            }
+            "instrs" => print!(
+                "{}",
+                disasm::disassemble(
+                    &self.instructions,
+                    &self.consts,
+                    &self.variables,
+                    &self.ctx.functions
+                )
+            ),
+            "reset" => *self = Session::new(),
+            "quit" | "q" | "exit" => return false,
+            unknown => eprintln!("Unknown command :{unknown} (see :help)"),
+        }
+        true
+    }
+}
+
+pub fn repl(limits: &Limits) {
+    let mut rl = DefaultEditor::new().unwrap_or_else(|e| {
+        error!(format_args!("Unable to start the REPL: {e}"));
+    });
+    let history = history_path();
+    if let Some(path) = &history {
+        // there is no history the first time the REPL is launched
+        let _ = rl.load_history(path);
+    }
+
+    println!(
+        "Spock {} REPL -- type :help for help",
+        env!("CARGO_PKG_VERSION")
+    );
+    let mut session = Session::new();
+    let mut buffer = String::new();
+    loop {
+        let prompt = if buffer.is_empty() { ">>> " } else { "... " };
+        let line = match rl.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                error!(format_args!("Unable to read the input: {e}"));
+            }
+        };
+
+        if buffer.is_empty() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(cmd) = line.trim().strip_prefix(':') {
+                let _ = rl.add_history_entry(line.trim());
+                if !session.command(cmd.trim()) {
+                    break;
+                }
+                continue;
+            }
+        }
+
+        buffer.push_str(&line);
+        buffer.push('\n');
+        match parse_entry(&buffer) {
+            Entry::Incomplete => continue,
+            Entry::Invalid(errors) => {
+                for e in errors {
+                    eprintln!("{} {e}", "Syntax error:".red());
+                }
+            }
+            Entry::Code(code) => session.run(code, &buffer, limits),
+        }
+        let _ = rl.add_history_entry(buffer.trim_end());
+        buffer.clear();
+    }
+
+    if let Some(path) = &history {
+        let _ = rl.save_history(path);
+    }
+}
@@ -0,0 +1,102 @@
+//! Locations in the source code, for error messages
+
+use serde::{Deserialize, Serialize};
+
+/// Byte range of the source code an expression comes from
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+
+/// A source file being compiled, to turn byte offsets into lines and columns
+#[derive(Debug, Default)]
+pub struct SourceFile {
+    pub name: String,
+    text: String,
+    // byte offset of the start of each line
+    lines: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: String, text: &str) -> Self {
+        let lines = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        SourceFile {
+            name,
+            text: text.to_string(),
+            lines,
+        }
+    }
+
+    /// Line and column (both starting at 1, the column counting characters) of a byte offset
+    pub fn line_column(&self, position: usize) -> (u32, u32) {
+        let line = self.lines.partition_point(|x| *x <= position) - 1;
+        let start = self.lines[line];
+        let column = self.text[start..position.min(self.text.len())]
+            .chars()
+            .count();
+        (line as u32 + 1, column as u32 + 1)
+    }
+
+    /// `file:line:column` of a byte offset
+    pub fn describe(&self, position: usize) -> String {
+        let (line, column) = self.line_column(position);
+        format!("{}:{line}:{column}", self.name)
+    }
+}
+
+/// Where an instruction comes from in the source code
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    // index in `DebugInfo::files`
+    pub file: u16,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Location of every instruction of a program, empty for hand-written assembly
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    pub locations: Vec<Location>,
+}
+
+impl DebugInfo {
+    /// Adds the locations of instructions compiled from `source`
+    pub fn add(&mut self, source: &SourceFile, spans: &[Span]) {
+        let file = match self.files.iter().position(|x| *x == source.name) {
+            Some(file) => file,
+            None => {
+                self.files.push(source.name.clone());
+                self.files.len() - 1
+            }
+        };
+        self.locations.extend(spans.iter().map(|span| {
+            let (line, column) = source.line_column(span.start);
+            Location {
+                file: file as u16,
+                line,
+                column,
+            }
+        }));
+    }
+
+    /// ` at file:line:column` for the instruction `i`, or nothing if its location is unknown
+    pub fn at(&self, i: usize) -> String {
+        match self.locations.get(i) {
+            Some(x) => format!(
+                " at {}:{}:{}",
+                self.files[x.file as usize], x.line, x.column
+            ),
+            None => String::new(),
+        }
+    }
+}